@@ -0,0 +1,126 @@
+use crate::output::{Formatted, PositionWriter};
+use crate::position::PbrPosition;
+use std::io::{self, Write};
+
+/// A half-open interval of positions that passed the filters.
+struct Interval {
+    chrom: String,
+    start: u32,
+    stop: u32,
+    /// number of positions in the interval and the sum of their depth.
+    positions: u64,
+    depth: u64,
+}
+
+/// Writes the positions as merged BED intervals.
+pub(crate) struct BedWriter<W: Write> {
+    out: W,
+    /// intervals separated by at most this many bases are merged.
+    min_gap: u32,
+    /// whether to write the mean depth of the positions as the score.
+    score: bool,
+    interval: Option<Interval>,
+}
+
+impl<W: Write> BedWriter<W> {
+    pub(crate) fn new(out: W, min_gap: u32, score: bool) -> Self {
+        BedWriter {
+            out,
+            min_gap,
+            score,
+            interval: None,
+        }
+    }
+
+    fn write_interval(&mut self, iv: &Interval) -> io::Result<()> {
+        write!(self.out, "{}\t{}\t{}", iv.chrom, iv.start, iv.stop)?;
+        if self.score {
+            // BED scores are integers from 0 to 1000.
+            let mean = iv.depth as f64 / iv.positions as f64;
+            write!(self.out, "\t.\t{}", mean.round().min(1000.0) as u32)?;
+        }
+        writeln!(self.out)
+    }
+}
+
+impl<W: Write> PositionWriter for BedWriter<W> {
+    fn write(&mut self, ps: &[PbrPosition], _formatted: &Formatted) -> io::Result<()> {
+        let p = &ps[0];
+        let depth = ps.iter().map(|p| p.depth as u64).sum::<u64>();
+        if let Some(iv) = self.interval.as_mut() {
+            if iv.chrom == p.ref_seq && p.pos <= iv.stop + self.min_gap {
+                iv.stop = p.pos + 1;
+                iv.positions += 1;
+                iv.depth += depth;
+                return Ok(());
+            }
+        }
+        let next = Interval {
+            chrom: p.ref_seq.clone(),
+            start: p.pos,
+            stop: p.pos + 1,
+            positions: 1,
+            depth,
+        };
+        if let Some(iv) = self.interval.replace(next) {
+            self.write_interval(&iv)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if let Some(iv) = self.interval.take() {
+            self.write_interval(&iv)?;
+        }
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::position;
+
+    const POSITIONS: &[(&str, u32, u32)] = &[
+        ("chr1", 10, 4),
+        ("chr1", 11, 6),
+        ("chr1", 14, 2),
+        ("chr1", 18, 3),
+        ("chr2", 18, 3),
+    ];
+
+    fn bed_lines(positions: &[(&str, u32, u32)], min_gap: u32, score: bool) -> io::Result<String> {
+        let mut out = vec![];
+        let mut w = BedWriter::new(&mut out, min_gap, score);
+        for &(chrom, pos, depth) in positions {
+            w.write(&[position(chrom, pos, depth, None)], &Formatted::Default)?;
+        }
+        w.finish()?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_bed_intervals() -> io::Result<()> {
+        assert_eq!(
+            bed_lines(POSITIONS, 0, false)?,
+            "chr1\t10\t12\nchr1\t14\t15\nchr1\t18\t19\nchr2\t18\t19\n"
+        );
+        // a gap of 2 bases (12 and 13) is bridged but not one of 3 (15 to 17).
+        assert_eq!(
+            bed_lines(POSITIONS, 2, true)?,
+            "chr1\t10\t15\t.\t4\nchr1\t18\t19\t.\t3\nchr2\t18\t19\t.\t3\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_bed_score() -> io::Result<()> {
+        // the mean depth is rounded and capped at 1000.
+        let positions = [("chr1", 10, 1), ("chr1", 11, 2), ("chr2", 5, 1500)];
+        assert_eq!(
+            bed_lines(&positions, 0, true)?,
+            "chr1\t10\t12\t.\t2\nchr2\t5\t6\t.\t1000\n"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,104 @@
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::Mutex;
+
+/// What is done with a read when evaluating an expression for it raises an error.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ErrorPolicy {
+    /// exit with the error
+    Fail,
+    /// filter the read
+    #[default]
+    Skip,
+    /// keep the read as if it passed the expression
+    Keep,
+}
+
+/// Number of times each distinct error is printed as it occurs.
+const PRINTED: u64 = 3;
+
+/// Counts the errors raised by the read expressions in all worker threads so
+/// that each distinct message is printed only a few times and summarized at
+/// the end.
+#[derive(Default)]
+pub(crate) struct ExpressionErrors {
+    policy: ErrorPolicy,
+    counts: Mutex<HashMap<String, u64>>,
+}
+
+impl ExpressionErrors {
+    pub(crate) fn new(policy: ErrorPolicy) -> Self {
+        ExpressionErrors {
+            policy,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record an error and return whether the read is kept.
+    pub(crate) fn record(&self, error: &dyn Display) -> bool {
+        let message = error.to_string();
+        if self.policy == ErrorPolicy::Fail {
+            eprintln!("Error evaluating expression: {}", message);
+            std::process::exit(1);
+        }
+        let mut counts = self.counts.lock().unwrap();
+        let n = counts.entry(message).or_insert(0);
+        *n += 1;
+        if *n <= PRINTED {
+            eprintln!("Error evaluating expression: {}", error);
+            if *n == PRINTED {
+                eprintln!("further occurrences of this error are only counted");
+            }
+        }
+        self.policy == ErrorPolicy::Keep
+    }
+
+    /// Each distinct error and its number of occurrences, most frequent first.
+    pub(crate) fn summary(&self) -> Vec<(String, u64)> {
+        let mut summary: Vec<_> = self
+            .counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(m, n)| (m.clone(), *n))
+            .collect();
+        summary.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        summary
+    }
+
+    /// Print the number of occurrences of each distinct error.
+    pub(crate) fn report(&self) {
+        let action = match self.policy {
+            ErrorPolicy::Keep => "kept",
+            _ => "skipped",
+        };
+        for (message, n) in self.summary() {
+            eprintln!(
+                "{} evaluations raised this error and the reads were {}: {}",
+                n, action, message
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expression_errors() {
+        let errors = ExpressionErrors::new(ErrorPolicy::Skip);
+        for message in ["missing tag", "bad value", "missing tag", "missing tag"] {
+            assert!(!errors.record(&message));
+        }
+        assert_eq!(
+            errors.summary(),
+            vec![
+                (String::from("missing tag"), 3),
+                (String::from("bad value"), 1)
+            ]
+        );
+        assert!(ExpressionErrors::new(ErrorPolicy::Keep).record(&"missing tag"));
+    }
+}
@@ -0,0 +1,302 @@
+use crate::output::{check_extra, sample_columns, Column, Formatted, PositionWriter};
+use crate::position::PbrPosition;
+use arrow::array::{
+    ArrayRef, BooleanBuilder, Float64Builder, StringArray, StringBuilder, StringDictionaryBuilder,
+    UInt32Builder,
+};
+use arrow::datatypes::{DataType, Field, Int32Type, Schema, SchemaRef};
+use arrow::ipc::writer::FileWriter;
+use arrow::record_batch::RecordBatch;
+use rust_htslib::bam::HeaderView;
+use std::io::{self, Write};
+use std::sync::Arc;
+
+/// Number of positions in each record batch.
+const BATCH_SIZE: usize = 65536;
+
+/// Collects the values of a column for the current batch.
+enum Builder {
+    Chrom(StringDictionaryBuilder<Int32Type>),
+    Str(StringBuilder),
+    Bool(BooleanBuilder),
+    Count(UInt32Builder),
+    Value(Float64Builder),
+}
+
+impl Builder {
+    /// The chromosome dictionary has all chromosomes of the header so that it
+    /// is the same in every batch, as required for an IPC file.
+    fn new(column: Column, chroms: &StringArray) -> io::Result<Self> {
+        Ok(match column {
+            Column::Chrom => Builder::Chrom(
+                StringDictionaryBuilder::new_with_dictionary(BATCH_SIZE, chroms)
+                    .map_err(io::Error::other)?,
+            ),
+            Column::RefBase => Builder::Str(StringBuilder::new()),
+            Column::NearMaxDepth => Builder::Bool(BooleanBuilder::new()),
+            Column::MeanBq | Column::MedianBq | Column::MeanMapq => {
+                Builder::Value(Float64Builder::new())
+            }
+            _ => Builder::Count(UInt32Builder::new()),
+        })
+    }
+
+    fn append(&mut self, column: Column, p: &PbrPosition) {
+        match self {
+            Builder::Chrom(b) => b.append_value(&p.ref_seq),
+            Builder::Str(b) => b.append_option(p.ref_base.map(String::from)),
+            Builder::Bool(b) => b.append_value(p.near_max_depth),
+            Builder::Count(b) => b.append_option(column.count(p)),
+            Builder::Value(b) => b.append_option(column.value(p)),
+        }
+    }
+
+    fn finish(&mut self) -> ArrayRef {
+        match self {
+            Builder::Chrom(b) => Arc::new(b.finish()),
+            Builder::Str(b) => Arc::new(b.finish()),
+            Builder::Bool(b) => Arc::new(b.finish()),
+            Builder::Count(b) => Arc::new(b.finish()),
+            Builder::Value(b) => Arc::new(b.finish()),
+        }
+    }
+}
+
+fn field(column: Column, name: &str) -> Field {
+    let data_type = match column {
+        Column::Chrom => DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8)),
+        Column::RefBase => DataType::Utf8,
+        Column::NearMaxDepth => DataType::Boolean,
+        Column::MeanBq | Column::MedianBq | Column::MeanMapq => DataType::Float64,
+        _ => DataType::UInt32,
+    };
+    let nullable = matches!(
+        column,
+        Column::RefBase
+            | Column::RefCount
+            | Column::AltCount
+            | Column::MeanBq
+            | Column::MedianBq
+            | Column::MeanMapq
+    );
+    Field::new(name, data_type, nullable)
+}
+
+/// Writes positions as record batches of an Arrow IPC file with a typed column
+/// for each of the --columns (laid out as in the tab-delimited output) and a
+/// string column for each extra column. The schema is created with the first
+/// position.
+pub(crate) struct ArrowWriter<W: Write> {
+    /// the output until the schema is known.
+    out: Option<W>,
+    writer: Option<FileWriter<W>>,
+    schema: SchemaRef,
+    chroms: StringArray,
+    columns: Vec<(Column, usize)>,
+    names: Vec<String>,
+    builders: Vec<Builder>,
+    extra_columns: Vec<String>,
+    extra_builders: Vec<StringBuilder>,
+    rows: usize,
+}
+
+impl<W: Write> ArrowWriter<W> {
+    pub(crate) fn new(
+        out: W,
+        header: &HeaderView,
+        columns: Vec<Column>,
+        samples: &[String],
+    ) -> io::Result<Self> {
+        let chroms: StringArray = (0..header.target_count())
+            .map(|tid| Some(String::from_utf8_lossy(header.tid2name(tid)).to_string()))
+            .collect();
+        let (columns, names) = sample_columns(&columns, samples);
+        let builders = columns
+            .iter()
+            .map(|(c, _)| Builder::new(*c, &chroms))
+            .collect::<io::Result<_>>()?;
+        Ok(ArrowWriter {
+            out: Some(out),
+            writer: None,
+            schema: Arc::new(Schema::empty()),
+            chroms,
+            columns,
+            names,
+            builders,
+            extra_columns: vec![],
+            extra_builders: vec![],
+            rows: 0,
+        })
+    }
+
+    /// Add the extra columns (--output-columns) of the output expression. A
+    /// row without a value for one of these has a null in its place.
+    pub(crate) fn with_extra_columns(mut self, extra_columns: Vec<String>) -> Self {
+        self.extra_builders = extra_columns.iter().map(|_| StringBuilder::new()).collect();
+        self.extra_columns = extra_columns;
+        self
+    }
+
+    fn start(&mut self) -> io::Result<()> {
+        let Some(out) = self.out.take() else {
+            return Ok(());
+        };
+        let fields = self
+            .columns
+            .iter()
+            .zip(&self.names)
+            .map(|((c, _), n)| field(*c, n));
+        let extra_fields = self
+            .extra_columns
+            .iter()
+            .map(|n| Field::new(n, DataType::Utf8, true));
+        self.schema = Arc::new(Schema::new(fields.chain(extra_fields).collect::<Vec<_>>()));
+        self.writer = Some(FileWriter::try_new(out, &self.schema).map_err(io::Error::other)?);
+        Ok(())
+    }
+
+    /// Write the positions collected so far as a record batch.
+    fn write_batch(&mut self) -> io::Result<()> {
+        if self.rows == 0 {
+            return Ok(());
+        }
+        let mut arrays: Vec<ArrayRef> = self.builders.iter_mut().map(|b| b.finish()).collect();
+        arrays.extend(
+            self.extra_builders
+                .iter_mut()
+                .map(|b| Arc::new(b.finish()) as ArrayRef),
+        );
+        // finishing the dictionary builder also clears its dictionary.
+        self.builders = self
+            .columns
+            .iter()
+            .map(|(c, _)| Builder::new(*c, &self.chroms))
+            .collect::<io::Result<_>>()?;
+        self.rows = 0;
+        let batch = RecordBatch::try_new(self.schema.clone(), arrays).map_err(io::Error::other)?;
+        let writer = self
+            .writer
+            .as_mut()
+            .expect("schema is created before writing");
+        writer.write(&batch).map_err(io::Error::other)
+    }
+}
+
+impl<W: Write> PositionWriter for ArrowWriter<W> {
+    fn write(&mut self, ps: &[PbrPosition], formatted: &Formatted) -> io::Result<()> {
+        let extra: &[(String, String)] = match formatted {
+            Formatted::Line(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "format can not return a line with --output-format arrow",
+                ))
+            }
+            Formatted::Columns(extra) => extra.as_slice(),
+            Formatted::Default => &[],
+        };
+        self.start()?;
+        check_extra(&self.extra_columns, extra)?;
+        for ((column, sample), builder) in self.columns.iter().zip(self.builders.iter_mut()) {
+            builder.append(*column, &ps[*sample]);
+        }
+        for (name, builder) in self
+            .extra_columns
+            .iter()
+            .zip(self.extra_builders.iter_mut())
+        {
+            builder.append_option(extra.iter().find(|(k, _)| k == name).map(|(_, v)| v));
+        }
+        self.rows += 1;
+        if self.rows == BATCH_SIZE {
+            self.write_batch()?;
+        }
+        Ok(())
+    }
+
+    /// Write the last batch and the file footer.
+    fn finish(&mut self) -> io::Result<()> {
+        self.start()?;
+        self.write_batch()?;
+        let writer = self
+            .writer
+            .as_mut()
+            .expect("schema is created before finishing");
+        writer.finish().map_err(io::Error::other)?;
+        writer.get_mut().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{header_view, position};
+    use arrow::array::{Array, AsArray};
+    use arrow::datatypes::UInt32Type;
+    use arrow::ipc::reader::FileReader;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_arrow_output() -> io::Result<()> {
+        let mut out = vec![];
+        let columns = vec![Column::Chrom, Column::Pos0, Column::Depth, Column::RefCount];
+        let samples = [String::from("tumor"), String::from("normal")];
+        let mut w = ArrowWriter::new(
+            &mut out,
+            &header_view(&[("chr1", 1000), ("chr2", 1000)]),
+            columns,
+            &samples,
+        )?
+        .with_extra_columns(vec![String::from("vaf")]);
+        w.write(
+            &[position("chr1", 3, 4, None), position("chr1", 3, 0, None)],
+            &Formatted::Default,
+        )?;
+        let extra = Formatted::Columns(vec![(String::from("vaf"), String::from("0.5"))]);
+        w.write(
+            &[position("chr2", 7, 2, None), position("chr2", 7, 1, None)],
+            &extra,
+        )?;
+        w.finish()?;
+        drop(w);
+
+        let reader = FileReader::try_new(Cursor::new(out), None).map_err(io::Error::other)?;
+        let names: Vec<_> = reader
+            .schema()
+            .fields()
+            .iter()
+            .map(|f| f.name().clone())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "chrom",
+                "pos0",
+                "tumor_depth",
+                "tumor_ref_count",
+                "normal_depth",
+                "normal_ref_count",
+                "vaf"
+            ]
+        );
+        let batches = reader
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(io::Error::other)?;
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        assert_eq!(batch.num_rows(), 2);
+
+        let chrom = batch.column(0).as_dictionary::<Int32Type>();
+        assert_eq!(chrom.keys().values().to_vec(), vec![0, 1]);
+        assert_eq!(chrom.values().len(), 2);
+        let pos = batch.column(1).as_primitive::<UInt32Type>();
+        assert_eq!(pos.values().to_vec(), vec![3, 7]);
+        let depth = batch.column(4).as_primitive::<UInt32Type>();
+        assert_eq!(depth.values().to_vec(), vec![0, 1]);
+        // there is no reference base without --fasta.
+        assert_eq!(batch.column(3).null_count(), 2);
+        // the schema does not depend on the first row.
+        let vaf = batch.column(6).as_string::<i32>();
+        assert_eq!((vaf.is_null(0), vaf.value(1)), (true, "0.5"));
+        Ok(())
+    }
+}
@@ -1,16 +1,36 @@
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
+mod bed;
 mod cached_faidx;
+mod errors;
+mod ipc;
+mod output;
+mod position;
 mod processor;
+mod read_metrics;
+mod summary;
+#[cfg(test)]
+mod test_utils;
+mod vcf;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use bed::BedWriter;
 use cached_faidx::CachedFaidx;
-use clap::Parser;
-use processor::{excluded, BasicProcessor};
+use clap::{Parser, ValueEnum};
+use errors::{ErrorPolicy, ExpressionErrors};
+use ipc::ArrowWriter;
+use output::{
+    is_bgzf, open_output, tabix_index, Column, Formatted, OutputFormat, PositionWriter, TsvWriter,
+};
+use position::{ColumnTally, PbrPosition};
+use processor::{excluded, BasicProcessor, Worker};
+use rust_lapper::Lapper;
+use summary::{Summarizer, TrinucleotideCounts};
+use vcf::VcfWriter;
 
 use mlua::prelude::*;
-use mlua::{Function, Value};
+use mlua::{FromLuaMulti, Function, Value};
 use perbase_lib::{
     par_granges::{self, RegionProcessor},
     position::pileup_position::PileupPosition,
@@ -18,39 +38,375 @@ use perbase_lib::{
 };
 use rust_htslib::bam::{
     self,
-    pileup::Alignment,
-    record::{Aux, Cigar, Record},
+    pileup::{Alignment, Indel},
+    record::{Aux, Cigar, CigarString, Record},
     Read,
 };
-use std::path::PathBuf;
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// The parts of the pileup `Alignment` at the current column that are exposed
+/// on the `read` object in addition to `qpos`.
+#[derive(Clone, Copy, Debug, Default)]
+struct PileupAlignment {
+    is_del: bool,
+    is_refskip: bool,
+    is_head: bool,
+    is_tail: bool,
+    /// length of the indel starting at the next base; positive for insertions,
+    /// negative for deletions.
+    indel: i64,
+}
+
+impl From<&Alignment<'_>> for PileupAlignment {
+    fn from(a: &Alignment<'_>) -> Self {
+        PileupAlignment {
+            is_del: a.is_del(),
+            is_refskip: a.is_refskip(),
+            is_head: a.is_head(),
+            is_tail: a.is_tail(),
+            indel: match a.indel() {
+                Indel::Ins(l) => l as i64,
+                Indel::Del(l) => -(l as i64),
+                Indel::None => 0,
+            },
+        }
+    }
+}
+
+impl mlua::UserData for PileupAlignment {}
+
+/// Get the alignment set on the read userdata or the default if the read
+/// was not evaluated from a pileup.
+fn pileup_alignment(ud: &mlua::AnyUserData) -> PileupAlignment {
+    ud.named_user_value::<mlua::UserDataRef<PileupAlignment>>("alignment")
+        .map(|a| *a)
+        .unwrap_or_default()
+}
+
+/// The 5' and 3' distances of the current site in the read or None if the read
+/// has no base at the site (a deletion) or was not evaluated in a pileup.
+fn end_distances(ud: &mlua::AnyUserData, aligned: bool) -> mlua::Result<Option<(i64, i64)>> {
+    let qpos = match ud.named_user_value::<usize>("qpos") {
+        Ok(qpos) if qpos != usize::MAX => qpos,
+        _ => return Ok(None),
+    };
+    ud.borrow_scoped::<Record, _>(|r| Some(read_metrics::end_distances(r, qpos, aligned)))
+}
+
+/// The pileup column currently being filtered. This is stored as app data on
+/// the lua state so that `read` fields can refer to the reference.
+#[derive(Default)]
+struct PileupColumn {
+    chrom: String,
+    pos: u32,
+    ref_base: Option<u8>,
+}
+
+/// Identifies a read across the columns that it covers.
+fn read_key(read: &Record) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    read.qname().hash(&mut hasher);
+    read.pos().hash(&mut hasher);
+    read.flags().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The reference positions of the mismatches of each read seen in the region,
+/// by `read_key`, so that they are found once rather than at every column.
+/// This is stored as app data on the lua state.
+#[derive(Default)]
+struct MismatchCache(HashMap<u64, Vec<i64>>);
+
+/// Reference positions of the read's mismatches using the fasta and column
+/// stored as app data.
+fn reference_mismatches(lua: &Lua, record: &Record) -> mlua::Result<Vec<i64>> {
+    let key = read_key(record);
+    if let Some(cache) = lua.app_data_ref::<MismatchCache>() {
+        if let Some(positions) = cache.0.get(&key) {
+            return Ok(positions.clone());
+        }
+    }
+    let positions = find_mismatches(lua, record)?;
+    if let Some(mut cache) = lua.app_data_mut::<MismatchCache>() {
+        cache.0.insert(key, positions.clone());
+    }
+    Ok(positions)
+}
+
+fn find_mismatches(lua: &Lua, record: &Record) -> mlua::Result<Vec<i64>> {
+    let Some(mut fai) = lua.app_data_mut::<CachedFaidx>() else {
+        return Err(LuaError::runtime(
+            "read mismatches require a reference fasta (--fasta)",
+        ));
+    };
+    let column = lua
+        .app_data_ref::<PileupColumn>()
+        .expect("column is set in LuaReadFilter::new");
+    let start = record.pos();
+    let end = record.cigar().end_pos();
+    if end <= start {
+        return Ok(vec![]);
+    }
+    let reference = fai
+        .fetch_seq(&column.chrom, start as usize, end as usize - 1)
+        .map_err(LuaError::external)?;
+    Ok(read_metrics::mismatch_positions(record, reference, start))
+}
+
+/// The position of the current pileup column.
+fn column_pos(lua: &Lua) -> i64 {
+    lua.app_data_ref::<PileupColumn>()
+        .map(|c| c.pos as i64)
+        .unwrap_or(-1)
+}
+
+/// Compile an expression, named `name` in error messages. A bare expression
+/// such as `read.mapping_quality > 10` is returned as is.
+fn compile(lua: &Lua, expression: &str, name: &str) -> mlua::Result<Function> {
+    let chunk_name = format!("={}", name);
+    let bare = lua
+        .load(format!("return {}", expression))
+        .set_name(&chunk_name)
+        .into_function();
+    match bare {
+        Ok(f) => Ok(f),
+        // report the error of the form that was probably intended.
+        Err(e) if !expression.contains("return") => Err(e),
+        Err(_) => lua.load(expression).set_name(&chunk_name).into_function(),
+    }
+}
+
+/// Decode the hex string of a `H` tag into its bytes.
+fn hex_bytes(hex: &str) -> mlua::Result<Vec<u8>> {
+    let invalid = || LuaError::runtime(format!("invalid hex byte array: {}", hex));
+    if hex.len() % 2 != 0 {
+        return Err(invalid());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                .ok_or_else(invalid)
+        })
+        .collect()
+}
+
+/// Convert an aux field to a lua value: a number, a string or a table of numbers
+/// for arrays. Hex byte arrays are returned as a string of the decoded bytes.
+fn aux_value(lua: &Lua, aux: Aux) -> mlua::Result<Value> {
+    Ok(match aux {
+        Aux::Char(v) => Value::String(lua.create_string(&[v])?),
+        Aux::I8(v) => Value::Number(v as f64),
+        Aux::U8(v) => Value::Number(v as f64),
+        Aux::I16(v) => Value::Number(v as f64),
+        Aux::U16(v) => Value::Number(v as f64),
+        Aux::I32(v) => Value::Number(v as f64),
+        Aux::U32(v) => Value::Number(v as f64),
+        Aux::Float(v) => Value::Number(v as f64),
+        Aux::Double(v) => Value::Number(v as f64),
+        Aux::String(v) => Value::String(lua.create_string(&v)?),
+        Aux::ArrayFloat(v) => {
+            let mut arr = Vec::new();
+            for i in 0..v.len() {
+                arr.push(v.get(i).unwrap_or(f32::NAN) as f32);
+            }
+            Value::Table(lua.create_sequence_from(arr)?)
+        }
+        Aux::ArrayI32(v) => {
+            let mut arr = Vec::new();
+            for i in 0..v.len() {
+                arr.push(v.get(i).unwrap_or(i32::MIN) as i32);
+            }
+            Value::Table(lua.create_sequence_from(arr)?)
+        }
+        Aux::ArrayI8(v) => {
+            let mut arr = Vec::new();
+            for i in 0..v.len() {
+                arr.push(v.get(i).unwrap_or(i8::MIN) as i8);
+            }
+            Value::Table(lua.create_sequence_from(arr)?)
+        }
+        Aux::ArrayU8(v) => {
+            let mut arr = Vec::new();
+            for i in 0..v.len() {
+                arr.push(v.get(i).unwrap_or(u8::MIN) as u8);
+            }
+            Value::Table(lua.create_sequence_from(arr)?)
+        }
+        Aux::ArrayU16(v) => {
+            let mut arr = Vec::new();
+            for i in 0..v.len() {
+                arr.push(v.get(i).unwrap_or(u16::MIN) as u16);
+            }
+            Value::Table(lua.create_sequence_from(arr)?)
+        }
+        Aux::ArrayU32(v) => {
+            let mut arr = Vec::new();
+            for i in 0..v.len() {
+                arr.push(v.get(i).unwrap_or(u32::MIN) as u32);
+            }
+            Value::Table(lua.create_sequence_from(arr)?)
+        }
+        Aux::ArrayI16(v) => {
+            let mut arr = Vec::new();
+            for i in 0..v.len() {
+                arr.push(v.get(i).unwrap_or(i16::MIN) as i16);
+            }
+            Value::Table(lua.create_sequence_from(arr)?)
+        }
+        Aux::HexByteArray(v) => Value::String(lua.create_string(hex_bytes(v)?)?),
+    })
+}
+
+/// SAM flag bits by their samtools names, available in lua as e.g. `flag.DUP`.
+const FLAGS: [(&str, u16); 12] = [
+    ("PAIRED", 0x1),
+    ("PROPER_PAIR", 0x2),
+    ("UNMAP", 0x4),
+    ("MUNMAP", 0x8),
+    ("REVERSE", 0x10),
+    ("MREVERSE", 0x20),
+    ("READ1", 0x40),
+    ("READ2", 0x80),
+    ("SECONDARY", 0x100),
+    ("QCFAIL", 0x200),
+    ("DUP", 0x400),
+    ("SUPPLEMENTARY", 0x800),
+];
+
+/// Set the global `flag` table of flag bits.
+fn register_flags(lua: &Lua) -> mlua::Result<()> {
+    let flag = lua.create_table_with_capacity(0, FLAGS.len())?;
+    for (name, bit) in FLAGS {
+        flag.set(name, bit)?;
+    }
+    lua.globals().set("flag", flag)
+}
 
-struct LuaReadFilter<'a> {
-    lua: &'a Lua,
+struct LuaReadFilter {
+    lua: Lua,
     filter_func: Function,
+    /// optional expression that depends only on the read and not the position.
+    read_func: Option<Function>,
+    /// memoized results of `read_func` keyed by read identity.
+    read_results: RefCell<HashMap<u64, bool>>,
+    /// errors raised by the expressions and what to do with the read.
+    errors: Arc<ExpressionErrors>,
 }
 
-impl<'a> LuaReadFilter<'a> {
+impl LuaReadFilter {
     // Create a new LuaReadFilter instance with the given expression
-    fn new(expression: &str, lua: &'a Lua) -> Result<Self> {
-        let filter_func = lua.load(expression).into_function()?;
+    // and optional fasta used to look up reference bases.
+    fn new(expression: &str, lua: Lua, fai: Option<CachedFaidx>) -> Result<Self> {
+        register_flags(&lua)?;
+        let filter_func = compile(&lua, expression, "expression")?;
+        Self::from_function(filter_func, lua, fai)
+    }
+
+    // Create a new LuaReadFilter from a script that defines `read_filter(read)`.
+    // All reads are kept if the script does not define `read_filter`.
+    fn from_script(script: &str, lua: Lua, fai: Option<CachedFaidx>) -> Result<Self> {
+        register_flags(&lua)?;
+        lua.load(script).set_name("=script").exec()?;
+        let filter_func = match lua.globals().get::<Option<Function>>("read_filter")? {
+            Some(f) => f,
+            None => lua.create_function(|_, ()| Ok(true))?,
+        };
+        Self::from_function(filter_func, lua, fai)
+    }
+
+    fn from_function(filter_func: Function, lua: Lua, fai: Option<CachedFaidx>) -> Result<Self> {
+        if let Some(fai) = fai {
+            lua.set_app_data(fai);
+        }
+        lua.set_app_data(PileupColumn::default());
+        lua.set_app_data(MismatchCache::default());
         lua.register_userdata_type::<Record>(|reg| {
             reg.add_field_method_get("mapping_quality", |_, this| Ok(this.mapq()));
             reg.add_field_method_get("flags", |_, this| Ok(this.flags()));
+            // whether any of the bits in `mask` is set, e.g. read:has_flag(flag.DUP + flag.QCFAIL)
+            reg.add_method(
+                "has_flag",
+                |_, this, mask: u16| Ok(this.flags() & mask != 0),
+            );
+            reg.add_field_method_get("is_paired", |_, this| Ok(this.is_paired()));
+            reg.add_field_method_get("is_unmapped", |_, this| Ok(this.is_unmapped()));
+            reg.add_field_method_get("is_reverse", |_, this| Ok(this.is_reverse()));
+            reg.add_field_method_get("is_read1", |_, this| Ok(this.is_first_in_template()));
+            reg.add_field_method_get("is_read2", |_, this| Ok(this.is_last_in_template()));
+            reg.add_field_method_get("is_secondary", |_, this| Ok(this.is_secondary()));
+            reg.add_field_method_get("is_qcfail", |_, this| Ok(this.is_quality_check_failed()));
+            reg.add_field_method_get("is_duplicate", |_, this| Ok(this.is_duplicate()));
+            reg.add_field_method_get("is_supplementary", |_, this| Ok(this.is_supplementary()));
             reg.add_field_method_get("tid", |_, this| Ok(this.tid()));
             reg.add_field_method_get("start", |_, this| Ok(this.pos()));
             reg.add_field_method_get("stop", |_, this| Ok(this.cigar().end_pos()));
             reg.add_field_method_get("length", |_, this| Ok(this.seq_len()));
             reg.add_field_method_get("insert_size", |_, this| Ok(this.insert_size()));
+            reg.add_field_method_get("mate_tid", |_, this| Ok(this.mtid()));
+            reg.add_field_method_get("mate_start", |_, this| Ok(this.mpos()));
+            reg.add_field_method_get("mate_is_reverse", |_, this| Ok(this.is_mate_reverse()));
+            reg.add_field_method_get("is_proper_pair", |_, this| Ok(this.is_proper_pair()));
+            reg.add_field_method_get("is_first_in_template", |_, this| {
+                Ok(this.is_first_in_template())
+            });
+            reg.add_field_method_get("is_chimeric", |_, this| Ok(read_metrics::is_chimeric(this)));
+            reg.add_field_method_get("overlaps_mate_at_position", |lua, this| {
+                Ok(read_metrics::overlaps_mate_at(this, column_pos(lua)))
+            });
             reg.add_field_method_get("qname", |_, this| {
                 let q = this.qname();
                 Ok(std::str::from_utf8(q).unwrap_or("").to_string())
             });
-            reg.add_field_method_get("sequence", |_, this| {
-                let seq = this.seq();
-                Ok(std::str::from_utf8(&seq.as_bytes())
-                    .unwrap_or("")
-                    .to_string())
+            reg.add_field_method_get("sequence", |lua, this| {
+                lua.create_string(this.seq().as_bytes())
+            });
+            // indexes are 0-based; positions outside of the read give nil.
+            reg.add_method("base_at", |lua, this, i: i64| {
+                match usize::try_from(i).ok().filter(|i| *i < this.seq_len()) {
+                    Some(i) => Ok(Some(lua.create_string([this.seq()[i]])?)),
+                    None => Ok(None),
+                }
+            });
+            reg.add_method("qual_at", |_, this, i: i64| {
+                Ok(usize::try_from(i)
+                    .ok()
+                    .and_then(|i| this.qual().get(i).copied()))
+            });
+            // the table is created once for each evaluation of an expression.
+            reg.add_field_function_get("quals", |lua, this| {
+                if let Some(quals) = this.named_user_value::<Option<mlua::Table>>("quals")? {
+                    return Ok(quals);
+                }
+                let quals = this.borrow_scoped::<Record, _>(|r| {
+                    lua.create_sequence_from(r.qual().iter().copied())
+                })??;
+                this.set_named_user_value("quals", &quals)?;
+                Ok(quals)
             });
+            // count of `base` in the half-open 0-based range [start, stop) of
+            // the read sequence, the whole sequence by default.
+            reg.add_method(
+                "count_base",
+                |_, this, (base, start, stop): (String, Option<usize>, Option<usize>)| {
+                    let &[base] = base.as_bytes() else {
+                        return Err(LuaError::runtime("count_base needs a single base"));
+                    };
+                    let seq = this.seq();
+                    let stop = stop.unwrap_or(seq.len()).min(seq.len());
+                    let base = base.to_ascii_uppercase();
+                    Ok((start.unwrap_or(0)..stop)
+                        .filter(|i| seq[*i] == base)
+                        .count())
+                },
+            );
             reg.add_function("qpos", |_, this: mlua::AnyUserData| {
                 let r: Result<usize, LuaError> = this.named_user_value("qpos");
                 r
@@ -67,63 +423,47 @@ impl<'a> LuaReadFilter<'a> {
                     _ => r.qual()[qpos] as i32,
                 })
             });
-            reg.add_field_function_get("distance_from_5prime", |_, this| {
+            reg.add_field_function_get("base", |_, this| {
                 let qpos: usize = match this.named_user_value("qpos") {
                     Ok(qpos) => qpos,
-                    Err(_) => {
-                        return Ok(-1);
-                    }
+                    Err(_) => return Ok(None),
                 };
-                this.borrow_scoped::<Record, i32>(|r| {
-                    if r.is_reverse() {
-                        r.seq_len() as i32 - qpos as i32
-                    } else {
-                        qpos as i32
-                    }
+                this.borrow_scoped::<Record, Option<String>>(|r| match qpos {
+                    usize::MAX => None,
+                    _ => Some((r.seq()[qpos] as char).to_string()),
                 })
             });
+            reg.add_field_method_get("ref_base", |lua, _| {
+                Ok(lua
+                    .app_data_ref::<PileupColumn>()
+                    .and_then(|c| c.ref_base)
+                    .map(|b| (b.to_ascii_uppercase() as char).to_string()))
+            });
+            reg.add_field_function_get("is_del", |_, this| Ok(pileup_alignment(&this).is_del));
+            reg.add_field_function_get("is_refskip", |_, this| {
+                Ok(pileup_alignment(&this).is_refskip)
+            });
+            reg.add_field_function_get("is_head", |_, this| Ok(pileup_alignment(&this).is_head));
+            reg.add_field_function_get("is_tail", |_, this| Ok(pileup_alignment(&this).is_tail));
+            reg.add_field_function_get("indel", |_, this| Ok(pileup_alignment(&this).indel));
+            reg.add_field_function_get("distance_from_5prime", |_, this| {
+                Ok(end_distances(&this, false)?.map_or(-1, |(five, _)| five))
+            });
             reg.add_field_function_get("distance_from_3prime", |_, this| {
-                let qpos: usize = match this.named_user_value("qpos") {
-                    Ok(qpos) => qpos,
-                    Err(_) => {
-                        return Ok(usize::MAX);
-                    }
-                };
-                this.borrow_scoped::<Record, usize>(|r| {
-                    if r.is_reverse() {
-                        qpos
-                    } else {
-                        r.seq_len() - qpos
-                    }
-                })
+                Ok(end_distances(&this, false)?.map_or(-1, |(_, three)| three))
+            });
+            reg.add_field_function_get("aligned_distance_from_5prime", |_, this| {
+                Ok(end_distances(&this, true)?.map_or(-1, |(five, _)| five))
+            });
+            reg.add_field_function_get("aligned_distance_from_3prime", |_, this| {
+                Ok(end_distances(&this, true)?.map_or(-1, |(_, three)| three))
             });
 
             reg.add_method("n_proportion_3_prime", |_, this, n_bases: usize| {
-                let seq = this.seq();
-                let mut count = 0;
-                let reverse = this.is_reverse();
-                for i in 0..n_bases {
-                    let base =
-                        seq[if reverse { i } else { seq.len() - 1 - i }].to_ascii_uppercase();
-                    if base == b'N' {
-                        count += 1;
-                    }
-                }
-                Ok(count as f64 / n_bases as f64)
+                Ok(read_metrics::n_proportion(this, n_bases, false))
             });
-
             reg.add_method("n_proportion_5_prime", |_, this, n_bases: usize| {
-                let seq = this.seq();
-                let mut count = 0;
-                let reverse = this.is_reverse();
-                for i in 0..n_bases {
-                    let base =
-                        seq[if reverse { seq.len() - 1 - i } else { i }].to_ascii_uppercase();
-                    if base == b'N' {
-                        count += 1;
-                    }
-                }
-                Ok(count as f64 / n_bases as f64)
+                Ok(read_metrics::n_proportion(this, n_bases, true))
             });
 
             reg.add_field_method_get("indel_count", |_, this| {
@@ -139,6 +479,40 @@ impl<'a> LuaReadFilter<'a> {
                 }
                 Ok(count)
             });
+            reg.add_field_method_get("cigar", |_, this| Ok(this.cigar().to_string()));
+            reg.add_method("cigar_ops", |lua, this, ()| {
+                let ops = lua.create_table_with_capacity(this.cigar_len(), 0)?;
+                for op in this.cigar().iter() {
+                    let t = lua.create_table_with_capacity(0, 2)?;
+                    t.set("op", op.char().to_string())?;
+                    t.set("len", op.len())?;
+                    ops.push(t)?;
+                }
+                Ok(ops)
+            });
+            reg.add_field_method_get("aligned_length", |_, this| {
+                Ok(read_metrics::aligned_length(this))
+            });
+            reg.add_field_method_get("reference_length", |_, this| {
+                Ok(this.cigar().end_pos() - this.pos())
+            });
+            reg.add_field_method_get("max_indel_length", |_, this| {
+                Ok(read_metrics::max_indel_length(this))
+            });
+
+            reg.add_field_method_get("mismatch_count", |lua, this| {
+                Ok(reference_mismatches(lua, this)?.len())
+            });
+            reg.add_method("mismatches_in_window", |lua, this, n: i64| {
+                let pos = column_pos(lua);
+                Ok(reference_mismatches(lua, this)?
+                    .iter()
+                    .filter(|p| (**p - pos).abs() <= n)
+                    .count())
+            });
+            reg.add_method("indels_in_window", |lua, this, n: i64| {
+                Ok(read_metrics::indels_in_window(this, column_pos(lua), n))
+            });
 
             reg.add_field_method_get("soft_clips_3_prime", |_, this| {
                 let cigar = this.cigar();
@@ -156,6 +530,22 @@ impl<'a> LuaReadFilter<'a> {
                     Ok(cigar.leading_softclips())
                 }
             });
+            reg.add_field_method_get("hard_clips_3_prime", |_, this| {
+                let cigar = this.cigar();
+                if this.is_reverse() {
+                    Ok(cigar.leading_hardclips())
+                } else {
+                    Ok(cigar.trailing_hardclips())
+                }
+            });
+            reg.add_field_method_get("hard_clips_5_prime", |_, this| {
+                let cigar = this.cigar();
+                if this.is_reverse() {
+                    Ok(cigar.trailing_hardclips())
+                } else {
+                    Ok(cigar.leading_hardclips())
+                }
+            });
             reg.add_field_method_get("average_base_quality", |_, this| {
                 let qual = this.qual();
                 let sum = qual.iter().map(|q| *q as u64).sum::<u64>();
@@ -163,207 +553,478 @@ impl<'a> LuaReadFilter<'a> {
                 Ok(sum as f64 / count as f64)
             });
 
-            reg.add_method("tag", |lua, this: &Record, tag: String| {
-                let tag = tag.as_bytes();
-                let aux = this.aux(tag).map_err(LuaError::external)?;
-                let lua_val: Value = match aux {
-                    Aux::Char(v) => Value::String(lua.create_string(&[v])?),
-                    Aux::I8(v) => Value::Number(v as f64),
-                    Aux::U8(v) => Value::Number(v as f64),
-                    Aux::I16(v) => Value::Number(v as f64),
-                    Aux::U16(v) => Value::Number(v as f64),
-                    Aux::I32(v) => Value::Number(v as f64),
-                    Aux::U32(v) => Value::Number(v as f64),
-                    Aux::Float(v) => Value::Number(v as f64),
-                    Aux::Double(v) => Value::Number(v as f64),
-                    Aux::String(v) => Value::String(lua.create_string(&v)?),
-                    Aux::ArrayFloat(v) => {
-                        let mut arr = Vec::new();
-                        for i in 0..v.len() {
-                            arr.push(v.get(i).unwrap_or(f32::NAN) as f32);
-                        }
-                        Value::Table(lua.create_sequence_from(arr)?)
-                    }
-                    Aux::ArrayI32(v) => {
-                        let mut arr = Vec::new();
-                        for i in 0..v.len() {
-                            arr.push(v.get(i).unwrap_or(i32::MIN) as i32);
-                        }
-                        Value::Table(lua.create_sequence_from(arr)?)
-                    }
-                    Aux::ArrayI8(v) => {
-                        let mut arr = Vec::new();
-                        for i in 0..v.len() {
-                            arr.push(v.get(i).unwrap_or(i8::MIN) as i8);
-                        }
-                        Value::Table(lua.create_sequence_from(arr)?)
-                    }
-                    Aux::ArrayU8(v) => {
-                        let mut arr = Vec::new();
-                        for i in 0..v.len() {
-                            arr.push(v.get(i).unwrap_or(u8::MIN) as u8);
-                        }
-                        Value::Table(lua.create_sequence_from(arr)?)
-                    }
-                    Aux::ArrayU16(v) => {
-                        let mut arr = Vec::new();
-                        for i in 0..v.len() {
-                            arr.push(v.get(i).unwrap_or(u16::MIN) as u16);
-                        }
-                        Value::Table(lua.create_sequence_from(arr)?)
-                    }
-                    Aux::ArrayU32(v) => {
-                        let mut arr = Vec::new();
-                        for i in 0..v.len() {
-                            arr.push(v.get(i).unwrap_or(u32::MIN) as u32);
-                        }
-                        Value::Table(lua.create_sequence_from(arr)?)
-                    }
-                    Aux::ArrayI16(v) => {
-                        let mut arr = Vec::new();
-                        for i in 0..v.len() {
-                            arr.push(v.get(i).unwrap_or(i16::MIN) as i16);
-                        }
-                        Value::Table(lua.create_sequence_from(arr)?)
+            reg.add_method("has_tag", |_, this, tag: String| {
+                Ok(this.aux(tag.as_bytes()).is_ok())
+            });
+            // a missing tag is an error unless a default is given.
+            reg.add_method(
+                "tag",
+                |lua, this, (tag, default): (String, Option<Value>)| {
+                    let Ok(aux) = this.aux(tag.as_bytes()) else {
+                        return default
+                            .ok_or_else(|| LuaError::runtime(format!("read has no {} tag", tag)));
+                    };
+                    aux_value(lua, aux)
+                },
+            );
+            reg.add_method(
+                "tag_int",
+                |_, this, (tag, default): (String, Option<i64>)| {
+                    let Ok(aux) = this.aux(tag.as_bytes()) else {
+                        return Ok(default);
+                    };
+                    match aux {
+                        Aux::I8(v) => Ok(Some(v as i64)),
+                        Aux::U8(v) => Ok(Some(v as i64)),
+                        Aux::I16(v) => Ok(Some(v as i64)),
+                        Aux::U16(v) => Ok(Some(v as i64)),
+                        Aux::I32(v) => Ok(Some(v as i64)),
+                        Aux::U32(v) => Ok(Some(v as i64)),
+                        _ => Err(LuaError::runtime(format!("tag {} is not an integer", tag))),
                     }
-                    Aux::HexByteArray(v) => {
-                        let lstr = String::from_utf8_lossy(v.as_bytes()).to_string();
-                        Value::String(lua.create_string(&lstr)?)
+                },
+            );
+            reg.add_method(
+                "tag_str",
+                |lua, this, (tag, default): (String, Option<mlua::String>)| {
+                    let Ok(aux) = this.aux(tag.as_bytes()) else {
+                        return Ok(default);
+                    };
+                    match aux {
+                        Aux::Char(v) => Ok(Some(lua.create_string([v])?)),
+                        Aux::String(v) => Ok(Some(lua.create_string(v)?)),
+                        Aux::HexByteArray(v) => Ok(Some(lua.create_string(hex_bytes(v)?)?)),
+                        _ => Err(LuaError::runtime(format!("tag {} is not a string", tag))),
                     }
-                };
-                Ok(Some(lua_val))
-            })
+                },
+            );
         })?;
-        Ok(Self { lua, filter_func })
+        Ok(Self {
+            lua,
+            filter_func,
+            read_func: None,
+            read_results: RefCell::new(HashMap::new()),
+            errors: Arc::new(ExpressionErrors::default()),
+        })
+    }
+
+    /// Add an expression that is evaluated only once per read rather than at
+    /// every pileup column. Reads failing it are not passed to the per-position
+    /// expression.
+    fn with_read_expression(mut self, expression: &str) -> Result<Self> {
+        self.read_func = Some(compile(&self.lua, expression, "--read-expression")?);
+        Ok(self)
+    }
+
+    /// The read filter for the expressions of the processor with the
+    /// `string_count` helper.
+    fn from_processor(processor: &BasicProcessor, fai: Option<CachedFaidx>) -> Result<Self> {
+        let mut rf = if processor.script {
+            LuaReadFilter::from_script(&processor.expression, Lua::new(), fai)?
+        } else {
+            LuaReadFilter::new(&processor.expression, Lua::new(), fai)?
+        };
+        if let Some(read_expression) = &processor.read_expression {
+            rf = rf.with_read_expression(read_expression)?;
+        }
+        rf = rf.with_errors(processor.errors.clone());
+        let string_count = rf
+            .lua
+            .create_function(|_, (haystack, needle): (String, String)| {
+                if needle.chars().count() != 1 {
+                    return Err(LuaError::runtime(
+                        "string_count needle must be a single character",
+                    ));
+                }
+                let needle = needle.chars().next().unwrap();
+                Ok(haystack.chars().filter(|c| *c == needle).count())
+            })?;
+        rf.lua.globals().set("string_count", string_count)?;
+        Ok(rf)
+    }
+
+    /// Evaluate the read-level and, with `filter`, the per-position
+    /// expressions for `read` outside of a pileup, returning the first error.
+    fn check(&self, read: &Record, filter: bool) -> mlua::Result<()> {
+        let filter_func = filter.then_some(&self.filter_func);
+        for func in self.read_func.iter().chain(filter_func) {
+            self.try_eval(func, read, None)?;
+        }
+        Ok(())
+    }
+
+    /// Share the error counts (and policy) with other read filters.
+    fn with_errors(mut self, errors: Arc<ExpressionErrors>) -> Self {
+        self.errors = errors;
+        self
+    }
+
+    /// Forget the memoized read-level results and mismatches.
+    fn clear_read_cache(&self) {
+        self.read_results.borrow_mut().clear();
+        if let Some(mut cache) = self.lua.app_data_mut::<MismatchCache>() {
+            cache.0.clear();
+        }
+    }
+
+    /// Evaluate the read-level expression, reusing the result if the read has
+    /// been seen at a previous column.
+    fn filter_read_level(&self, read_func: &Function, read: &Record) -> bool {
+        let key = read_key(read);
+        if let Some(r) = self.read_results.borrow().get(&key) {
+            return *r;
+        }
+        let r = self.eval(read_func, read, None);
+        self.read_results.borrow_mut().insert(key, r);
+        r
+    }
+
+    /// Call `func` with `read` (and the alignment at this column) set as the
+    /// global `read`.
+    fn try_eval(
+        &self,
+        func: &Function,
+        read: &Record,
+        alignment: Option<&Alignment>,
+    ) -> mlua::Result<bool> {
+        self.lua.scope(|scope| {
+            let globals = self.lua.globals();
+            let ud = scope.create_any_userdata_ref(read)?;
+            if let Some(alignment) = alignment {
+                ud.set_named_user_value("qpos", alignment.qpos().unwrap_or(usize::MAX))?;
+                ud.set_named_user_value("alignment", PileupAlignment::from(alignment))?;
+            }
+
+            globals.set("read", ud.clone()).expect("error setting read");
+
+            func.call::<bool>(ud)
+        })
+    }
+
+    fn eval(&self, func: &Function, read: &Record, alignment: Option<&Alignment>) -> bool {
+        match self.try_eval(func, read, alignment) {
+            Ok(r) => r,
+            Err(e) => self.errors.record(&e),
+        }
+    }
+
+    /// Set the pileup column that subsequent reads are filtered at and return
+    /// its reference base if a fasta was given.
+    fn set_column(&self, chrom: &str, pos: u32) -> Option<char> {
+        let ref_base = self.lua.app_data_mut::<CachedFaidx>().map(|mut fai| {
+            let seq = fai
+                .fetch_seq(chrom, pos as usize, pos as usize)
+                .expect("error extracting reference base");
+            seq[0]
+        });
+        let mut column = self
+            .lua
+            .app_data_mut::<PileupColumn>()
+            .expect("column is set in LuaReadFilter::new");
+        if column.chrom != chrom {
+            column.chrom = chrom.to_string();
+        }
+        column.pos = pos;
+        column.ref_base = ref_base;
+        ref_base.map(|b| b as char)
+    }
+}
+
+/// Call `func` with the first sample's position set as the global `pile` and
+/// the positions of all samples as the global array `piles`. These are also
+/// passed as the arguments.
+fn call_with_piles<R: FromLuaMulti>(
+    lua: &Lua,
+    func: &Function,
+    ps: &[PbrPosition],
+) -> mlua::Result<R> {
+    lua.scope(|scope| {
+        let piles = lua.create_table_with_capacity(ps.len(), 0)?;
+        for p in ps {
+            piles.push(scope.create_any_userdata_ref(p)?)?;
+        }
+        let pile: Value = piles.get(1)?;
+        let globals = lua.globals();
+        globals.set("pile", pile.clone())?;
+        globals.set("piles", piles.clone())?;
+        func.call::<R>((pile, piles))
+    })
+}
+
+/// Convert a value returned from lua to the text written in an output column.
+fn lua_value_string(value: &Value) -> mlua::Result<String> {
+    Ok(match value {
+        Value::Nil => String::from("."),
+        Value::Boolean(b) => b.to_string(),
+        Value::Integer(i) => i.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => s.to_string_lossy(),
+        v => {
+            return Err(LuaError::runtime(format!(
+                "unsupported value for output column: {}",
+                v.type_name()
+            )))
+        }
+    })
+}
+
+/// The lua state and functions that are applied to each position in the main
+/// thread: the --pile-expression, --output-expression and the optional hooks
+/// from a script.
+struct PileFilter {
+    lua: Lua,
+    expression: Option<Function>,
+    pile_filter: Option<Function>,
+    format: Option<Function>,
+    finalize: Option<Function>,
+}
+
+impl PileFilter {
+    /// Compile the expressions and run the script, calling its `init()`.
+    /// The output expression takes precedence over the script's `format`.
+    fn new(
+        expression: Option<&str>,
+        output_expression: Option<&str>,
+        script: Option<&str>,
+    ) -> mlua::Result<Self> {
+        let lua = Lua::new();
+        register_pile(&lua)?;
+        let expression = match expression {
+            Some(expression) => Some(compile(&lua, expression, "--pile-expression")?),
+            None => None,
+        };
+        let (mut pile_filter, mut format, mut finalize) = (None, None, None);
+        if let Some(script) = script {
+            lua.load(script).set_name("=script").exec()?;
+            let globals = lua.globals();
+            if let Some(init) = globals.get::<Option<Function>>("init")? {
+                init.call::<()>(())?;
+            }
+            pile_filter = globals.get("pile_filter")?;
+            format = globals.get("format")?;
+            finalize = globals.get("finalize")?;
+        }
+        if let Some(output_expression) = output_expression {
+            format = Some(compile(&lua, output_expression, "--output-expression")?);
+        }
+        Ok(PileFilter {
+            lua,
+            expression,
+            pile_filter,
+            format,
+            finalize,
+        })
+    }
+
+    /// Whether the position passes the pile expression and `pile_filter(pile)`.
+    fn keep(&self, ps: &[PbrPosition]) -> mlua::Result<bool> {
+        for func in self.expression.iter().chain(self.pile_filter.iter()) {
+            if !call_with_piles::<bool>(&self.lua, func, ps)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Evaluate the output expression or the script's `format(pile)`. A table
+    /// gives extra columns, a string replaces the row and nil keeps the default.
+    fn format(&self, ps: &[PbrPosition]) -> mlua::Result<Formatted> {
+        let Some(func) = &self.format else {
+            return Ok(Formatted::Default);
+        };
+        match call_with_piles::<Value>(&self.lua, func, ps)? {
+            Value::Nil => Ok(Formatted::Default),
+            Value::String(s) => Ok(Formatted::Line(s.to_string_lossy())),
+            Value::Table(t) => {
+                let mut columns = vec![];
+                for pair in t.pairs::<String, Value>() {
+                    let (name, value) = pair?;
+                    columns.push((name, lua_value_string(&value)?));
+                }
+                columns.sort();
+                Ok(Formatted::Columns(columns))
+            }
+            v => Err(LuaError::runtime(format!(
+                "format must return nil, a string or a table; got {}",
+                v.type_name()
+            ))),
+        }
+    }
+
+    fn finalize(&self) -> mlua::Result<()> {
+        if let Some(func) = &self.finalize {
+            func.call::<()>(())?;
+        }
+        Ok(())
     }
 }
 
 fn register_pile(lua: &Lua) -> mlua::Result<()> {
-    lua.register_userdata_type::<PileupPosition>(|reg| {
-        reg.add_field_method_get("depth", |_, this| Ok(this.depth));
-        reg.add_field_method_get("a", |_, this| Ok(this.a));
-        reg.add_field_method_get("c", |_, this| Ok(this.c));
-        reg.add_field_method_get("g", |_, this| Ok(this.g));
-        reg.add_field_method_get("t", |_, this| Ok(this.t));
-        reg.add_field_method_get("n", |_, this| Ok(this.n));
-        reg.add_field_method_get("fail", |_, this| Ok(this.fail));
-        reg.add_field_method_get("ins", |_, this| Ok(this.ins));
-        reg.add_field_method_get("del", |_, this| Ok(this.del));
-        reg.add_field_method_get("ref_skip", |_, this| Ok(this.ref_skip));
+    lua.register_userdata_type::<PbrPosition>(|reg| {
         reg.add_field_method_get("pos", |_, this| Ok(this.pos));
+        // the counts and qualities that can also be output as columns.
+        for column in Column::value_variants() {
+            let column = *column;
+            match column {
+                Column::Chrom | Column::Pos0 | Column::RefBase | Column::NearMaxDepth => {}
+                Column::MeanBq | Column::MedianBq | Column::MeanMapq => {
+                    reg.add_field_method_get(column.name(), move |_, this| Ok(column.value(this)));
+                }
+                _ => reg.add_field_method_get(column.name(), move |_, this| Ok(column.count(this))),
+            }
+        }
     })
 }
 
-impl<'a> ReadFilter for LuaReadFilter<'a> {
+impl ReadFilter for LuaReadFilter {
     /// Filter reads based user expression.
     #[inline]
     fn filter_read(&self, read: &Record, alignment: Option<&Alignment>) -> bool {
-        let r = self.lua.scope(|scope| {
-            let globals = self.lua.globals();
-            let ud = scope.create_any_userdata_ref(read)?;
-            ud.set_named_user_value("qpos", alignment.unwrap().qpos().unwrap_or(usize::MAX))?;
-
-            globals.set("read", ud).expect("error setting read");
-
-            self.filter_func.call::<bool>(())
-        });
-
-        match r {
-            Ok(r) => r,
-            Err(e) => {
-                eprintln!("Error evaluating expression: {}", e);
-                false
+        if let Some(read_func) = &self.read_func {
+            if !self.filter_read_level(read_func, read) {
+                return false;
             }
         }
+        self.eval(&self.filter_func, read, alignment)
     }
 }
 
 impl RegionProcessor for BasicProcessor {
-    type P = PileupPosition;
+    /// The position in each sample, in the order of the alignment files.
+    type P = Vec<PbrPosition>;
 
     // This function receives an interval to examine.
     fn process_region(&self, tid: u32, start: u32, stop: u32) -> Vec<Self::P> {
-        let mut reader = bam::IndexedReader::from_path(&self.bamfile).expect("Indexed reader");
-        let mut fai = if let Some(fasta) = &self.fasta_path {
-            reader.set_reference(fasta).expect("reference");
-            Some(CachedFaidx::new(fasta).expect("error reading fasta"))
-        } else {
-            None
-        };
-
-        let header = reader.header().to_owned();
-        let lua = Lua::new();
-
-        let rf = LuaReadFilter::new(&self.expression, &lua).unwrap_or_else(|_| {
-            panic!(
-                "error creating lua read filter with expression {}",
-                &self.expression
-            )
-        });
-
-        let exclude_intervals = self.exclude_regions.as_ref().map(|regions_bed| {
-            Self::bed_to_intervals(&header, regions_bed, true).expect("BED file")
-        });
+        // take an idle worker or create one if all are in use by other threads.
+        let worker = self.workers.lock().unwrap().pop();
+        let mut worker = worker.unwrap_or_else(|| Worker::new(self));
+        let result = self.process_region_with(&mut worker, tid, start, stop);
+        self.workers.lock().unwrap().push(worker);
+        result
+    }
+}
 
-        let string_count = rf
-            .lua
-            .create_function(|_, (haystack, needle): (String, String)| {
-                assert!(needle.len() == 1);
-                let needle = needle.chars().next().unwrap();
-                Ok(haystack.chars().filter(|c| *c == needle).count())
+impl BasicProcessor {
+    fn process_region_with(
+        &self,
+        worker: &mut Worker,
+        tid: u32,
+        start: u32,
+        stop: u32,
+    ) -> Vec<Vec<PbrPosition>> {
+        let Worker {
+            readers,
+            rf,
+            exclude_intervals,
+        } = worker;
+        let header = readers[0].header().to_owned();
+        let chrom = unsafe { std::str::from_utf8_unchecked(header.target_names()[tid as usize]) };
+        let samples = readers
+            .iter_mut()
+            .map(|reader| {
+                // reads are only memoized within a region and sample.
+                rf.clear_read_cache();
+                self.sample_positions(reader, rf, exclude_intervals, &header, tid, start, stop)
             })
-            .expect("eror creating function");
-        rf.lua
-            .globals()
-            .set("string_count", string_count)
-            .expect("error setting string_count");
+            .collect();
+        merge_samples(chrom, samples)
+    }
 
+    #[allow(clippy::too_many_arguments)]
+    fn sample_positions(
+        &self,
+        reader: &mut bam::IndexedReader,
+        rf: &LuaReadFilter,
+        exclude_intervals: &Option<Vec<Lapper<u32, ()>>>,
+        header: &bam::HeaderView,
+        tid: u32,
+        start: u32,
+        stop: u32,
+    ) -> Vec<PbrPosition> {
         // fetch the region
         reader.fetch((tid, start, stop)).expect("Fetched ROI");
         // Walk over pileups
         let mut p = reader.pileup();
         let chrom = unsafe { std::str::from_utf8_unchecked(header.target_names()[tid as usize]) };
         p.set_max_depth(self.max_depth);
-        let mut result: Vec<PileupPosition> = p
-            .flat_map(|p| {
-                let pileup = p.expect("Extracted a pileup");
-                // Verify that we are within the bounds of the chunk we are iterating on
-                // Since pileup will pull reads that overhang edges.
-                if pileup.pos() >= start
+        p.flat_map(|p| {
+            let pileup = p.expect("Extracted a pileup");
+            // Verify that we are within the bounds of the chunk we are iterating on
+            // Since pileup will pull reads that overhang edges.
+            if pileup.pos() >= start
                     && pileup.pos() < stop
                     // and check if this position is excluded.
-                    && !excluded(&exclude_intervals, &pileup)
-                {
-                    if self.mate_fix {
-                        Some(PileupPosition::from_pileup_mate_aware(
-                            pileup, &header, &rf, None,
-                        ))
-                    } else {
-                        Some(PileupPosition::from_pileup(pileup, &header, &rf, None))
-                    }
+                    && !excluded(exclude_intervals, &pileup)
+            {
+                let ref_base = rf.set_column(chrom, pileup.pos());
+                // tally the strand of the reads that pass the filter.
+                let tally = ColumnTally::new(rf, self.mate_fix);
+                let mut position = if self.mate_fix {
+                    PileupPosition::from_pileup_mate_aware(pileup, header, &tally, None)
                 } else {
-                    None
-                }
-            })
-            .collect();
-        if let Some(fai) = &mut fai {
-            result.iter_mut().for_each(|p| {
-                let s = fai
-                    .fetch_seq(chrom, p.pos as usize, (p.pos + 1) as usize)
-                    .expect("error extracting reference base");
-                p.ref_base = Some(s[0] as char);
-            });
-        }
-        result
-    }
-}
-
+                    PileupPosition::from_pileup(pileup, header, &tally, None)
+                };
+                position.ref_base = ref_base;
+                Some(tally.finish(position))
+            } else {
+                None
+            }
+        })
+        .collect()
+    }
+}
+
+/// Combine the sorted positions from each sample into one entry per position
+/// with a position for every sample. Samples without coverage at a position get
+/// an empty position.
+fn merge_samples(chrom: &str, samples: Vec<Vec<PbrPosition>>) -> Vec<Vec<PbrPosition>> {
+    let mut positions: Vec<u32> = samples.iter().flatten().map(|p| p.pos).collect();
+    positions.sort_unstable();
+    positions.dedup();
+    let mut samples: Vec<_> = samples
+        .into_iter()
+        .map(|s| s.into_iter().peekable())
+        .collect();
+    positions
+        .into_iter()
+        .map(|pos| {
+            let mut ps: Vec<PbrPosition> = samples
+                .iter_mut()
+                .map(|s| {
+                    s.next_if(|p| p.pos == pos).unwrap_or_else(|| {
+                        PileupPosition {
+                            ref_seq: chrom.to_string(),
+                            pos,
+                            ..Default::default()
+                        }
+                        .into()
+                    })
+                })
+                .collect();
+            let ref_base = ps.iter().find_map(|p| p.ref_base);
+            for p in ps.iter_mut() {
+                p.ref_base = ref_base;
+            }
+            ps
+        })
+        .collect()
+}
+
 #[derive(Parser, Default, Debug)]
 #[command(author, version, about, long_about = None, arg_required_else_help = true)]
 struct Args {
-    #[arg(help = "Path to the bamfile")]
-    bam_path: PathBuf,
-    #[clap(help = "Lua expression to evaluate")]
+    #[arg(
+        help = "Path to the bamfile or comma-separated paths for multiple samples",
+        long_help = "Path to the bamfile or comma-separated paths to the alignment files of multiple samples. These must have the same sequences in their headers. The output has a group of columns for each sample",
+        value_delimiter = ',',
+        num_args = 1,
+        required = true
+    )]
+    bam_paths: Vec<PathBuf>,
+    #[clap(
+        help = "Lua expression to evaluate or path to a lua script",
+        long_help = "Lua expression to evaluate for each read or the path to a lua script that may define the functions init(), read_filter(read), pile_filter(pile), format(pile) and finalize()"
+    )]
     expression: String,
     #[clap(short, long, default_value = "2", help = "Number of threads to use")]
     threads: usize,
@@ -390,29 +1051,312 @@ struct Args {
 
     #[clap(short, long, help = "optional expression required for the pileup")]
     pile_expression: Option<String>,
+
+    #[clap(
+        short,
+        long,
+        help = "optional expression evaluated once per read",
+        long_help = "optional lua expression that only uses fields of the read that do not depend on the position (e.g. mapping_quality, flags, average_base_quality). It is evaluated once per read rather than at every column and reads that fail it are not passed to <EXPRESSION>"
+    )]
+    read_expression: Option<String>,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = ErrorPolicy::Skip,
+        help = "what to do with a read when an expression raises an error for it",
+        long_help = "what to do with a read when the expression or --read-expression raises an error for it: exit (fail), filter the read (skip) or keep it (keep). Each distinct error is printed for its first few occurrences and the number of occurrences of each is printed at the end"
+    )]
+    on_error: ErrorPolicy,
+
+    #[clap(
+        long,
+        help = "optional expression returning a table of extra output columns",
+        long_help = "optional lua expression evaluated for each output position that returns a table of name = value. The values are written in the extra columns given by --output-columns, e.g. --output-columns vaf --output-expression 'return {vaf = (pile.depth - pile.a) / pile.depth}'"
+    )]
+    output_expression: Option<String>,
+
+    #[clap(
+        long,
+        value_delimiter = ',',
+        help = "comma-separated names of the extra columns returned by --output-expression",
+        long_help = "comma-separated names of the extra columns returned by --output-expression (or the format function of a script), written in this order after --columns. A row without a value for a column has a missing value, while a name that is returned but not given here is an error"
+    )]
+    output_columns: Vec<String>,
+
+    #[clap(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = output::DEFAULT_COLUMNS,
+        help = "comma-separated list of columns to output"
+    )]
+    columns: Vec<Column>,
+
+    #[clap(
+        long,
+        help = "output callable bases and depth per BED interval (or chromosome) instead of per base",
+        long_help = "instead of a row per base, output one row per interval in --bedfile (or per chromosome without --bedfile) with the number of positions that passed the filters, their summed depth and their counts by reference base (requires --fasta)"
+    )]
+    summary: bool,
+
+    #[clap(
+        long,
+        requires = "fasta",
+        help = "output callable bases and depth per pyrimidine-centered reference trinucleotide",
+        long_help = "instead of a row per base, output the number of positions that passed the filters and their summed depth for each of the 32 pyrimidine-centered reference trinucleotides (e.g. for SBS-96 mutation signature opportunities). With --summary, the callable positions of each region are counted by trinucleotide in extra columns instead"
+    )]
+    trinucleotides: bool,
+
+    #[clap(long, value_enum, default_value_t = OutputFormat::Tsv, help = "format of the per-position output")]
+    output_format: OutputFormat,
+
+    #[clap(
+        long,
+        value_name = "TOLERANCE",
+        help = "with --output-format vcf, merge adjacent positions into reference blocks with END",
+        long_help = "with --output-format vcf, merge adjacent positions into gVCF-like reference blocks with END while the depth of each sample stays within TOLERANCE (a fraction, e.g. 0.2) of the minimum depth in the block. The records have the mean (DP) and minimum (MIN_DP) depth of each block"
+    )]
+    gvcf_blocks: Option<f64>,
+
+    #[clap(
+        long,
+        help = "with --output-format bed, merge intervals separated by at most this many bases (default 0)"
+    )]
+    min_gap: Option<u32>,
+
+    #[clap(
+        long,
+        help = "with --output-format bed, add the rounded mean depth of the passing positions as the score",
+        long_help = "with --output-format bed, add a name (.) and the mean depth of the passing positions, rounded and capped at 1000 as BED scores are integers from 0 to 1000, as the score"
+    )]
+    bed_score: bool,
+
+    #[clap(
+        short,
+        long,
+        help = "write to this file instead of stdout",
+        long_help = "write to this file instead of stdout. If the path ends with .gz, the output is bgzip-compressed and the per-position output is tabix-indexed (tsv only when its first columns are chrom and pos0)"
+    )]
+    output: Option<PathBuf>,
 }
 
-fn main() -> Result<()> {
-    println!("# pbr version {}", env!("CARGO_PKG_VERSION"));
+/// The sample name from the read groups of the header or the file name without
+/// its extension if no read group has a sample.
+fn sample_name(path: &Path, header: &bam::HeaderView) -> String {
+    let header = bam::Header::from_template(header).to_hashmap();
+    header
+        .get("RG")
+        .and_then(|rgs| rgs.iter().find_map(|rg| rg.get("SM").cloned()))
+        .unwrap_or_else(|| {
+            path.file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default()
+        })
+}
 
-    let opts = Args::parse();
+/// Check that the alignment files have the same sequences in their headers and
+/// return their sample names, which must be unique as they name the output
+/// columns.
+fn read_samples(paths: &[PathBuf]) -> Result<Vec<String>> {
+    let mut sequences = None;
+    let mut samples = vec![];
+    for path in paths {
+        let reader = bam::Reader::from_path(path)?;
+        let header = reader.header();
+        let seqs: Vec<_> = (0..header.target_count())
+            .map(|tid| (header.tid2name(tid).to_vec(), header.target_len(tid)))
+            .collect();
+        match &sequences {
+            None => sequences = Some(seqs),
+            Some(first) if *first != seqs => {
+                return Err(anyhow!(
+                    "{} does not have the same sequences in its header as {}",
+                    path.display(),
+                    paths[0].display()
+                ))
+            }
+            Some(_) => {}
+        }
+        let sample = sample_name(path, header);
+        if let Some(i) = samples.iter().position(|s| *s == sample) {
+            return Err(anyhow!(
+                "{} and {} have the same sample name: {}",
+                paths[i].display(),
+                path.display(),
+                sample
+            ));
+        }
+        samples.push(sample);
+    }
+    Ok(samples)
+}
 
-    if !opts.expression.contains("return") {
-        eprintln!("Expression '{}' must contain 'return'", opts.expression);
-        std::process::exit(1);
+/// A mapped forward read used to check the read expressions.
+fn synthetic_read() -> Record {
+    let mut read = Record::new();
+    let cigar = CigarString(vec![Cigar::Match(4)]);
+    read.set(b"pbr_check", Some(&cigar), b"ACGT", &[30; 4]);
+    read.set_tid(0);
+    read.set_pos(0);
+    read.set_mapq(60);
+    read
+}
+
+/// A covered position used to check the pile and output expressions.
+fn synthetic_pile(chrom: &str, ref_base: Option<char>) -> PbrPosition {
+    let mut p = PbrPosition::from(PileupPosition {
+        ref_seq: chrom.to_string(),
+        depth: 1,
+        a: 1,
+        ref_base,
+        ..Default::default()
+    });
+    p.quality.mean_bq = Some(30.0);
+    p.quality.median_bq = Some(30.0);
+    p.quality.mean_mapq = Some(60.0);
+    p
+}
+
+/// What was found by evaluating the expressions for a synthetic read and pile.
+#[derive(Debug, Default)]
+struct Checked {
+    /// errors raised by the evaluation.
+    warnings: Vec<String>,
+}
+
+/// Compile the expressions and evaluate them once with a synthetic read and
+/// pile so that errors are reported (with the line in the expression) before
+/// any region is processed rather than for every read. Compile errors are
+/// returned, while errors raised by the evaluation are returned as warnings
+/// as they may depend on the read (e.g. a missing tag). A script is only
+/// loaded and compiled: none of its hooks are called here, so `init()` runs
+/// once and nothing it prints ends up before the output.
+fn check_expressions(
+    processor: &BasicProcessor,
+    header: &bam::HeaderView,
+    pile_expression: Option<&str>,
+    output_expression: Option<&str>,
+    samples: usize,
+) -> Result<Checked> {
+    let fai = match &processor.fasta_path {
+        Some(fasta) => Some(CachedFaidx::new(fasta)?),
+        None => None,
+    };
+    let rf = LuaReadFilter::from_processor(processor, fai)?;
+    let (chrom, ref_base) = if header.target_count() > 0 {
+        let chrom = String::from_utf8_lossy(header.tid2name(0)).to_string();
+        let ref_base = rf.set_column(&chrom, 0);
+        (chrom, ref_base)
+    } else {
+        (String::new(), None)
+    };
+    let mut checked = Checked::default();
+    // a script's `read_filter` is a hook and is not called.
+    if let Err(e) = rf.check(&synthetic_read(), !processor.script) {
+        let warning = format!("error evaluating the read expression: {}", e);
+        checked.warnings.push(warning);
+    }
+
+    let pf = PileFilter::new(pile_expression, output_expression, None)?;
+    let ps: Vec<_> = (0..samples)
+        .map(|_| synthetic_pile(&chrom, ref_base))
+        .collect();
+    if let Err(e) = pf.keep(&ps) {
+        let warning = format!("error evaluating the pile filters: {}", e);
+        checked.warnings.push(warning);
     }
+    if let Err(e) = pf.format(&ps) {
+        let warning = format!("error evaluating the output format: {}", e);
+        checked.warnings.push(warning);
+    }
+    Ok(checked)
+}
 
+fn main() -> Result<()> {
+    let opts = Args::parse();
+
+    // the expression can also be a path to a script defining functions.
+    let script = if Path::new(&opts.expression).is_file() {
+        Some(std::fs::read_to_string(&opts.expression)?)
+    } else {
+        None
+    };
+
+    let samples = read_samples(&opts.bam_paths)?;
+    let header = bam::Reader::from_path(&opts.bam_paths[0])?
+        .header()
+        .to_owned();
+
+    let errors = Arc::new(ExpressionErrors::new(opts.on_error));
     let basic_processor = BasicProcessor {
-        bamfile: PathBuf::from(&opts.bam_path),
-        expression: String::from("") + opts.expression.as_str(),
+        bamfiles: opts.bam_paths.clone(),
+        expression: script.clone().unwrap_or_else(|| opts.expression.clone()),
+        script: script.is_some(),
+        read_expression: opts.read_expression,
         max_depth: opts.max_depth,
         exclude_regions: opts.exclude,
         mate_fix: opts.mate_fix,
         fasta_path: opts.fasta.clone(),
+        errors: errors.clone(),
+        workers: Mutex::new(Vec::new()),
     };
 
+    let checked = check_expressions(
+        &basic_processor,
+        &header,
+        opts.pile_expression.as_deref(),
+        opts.output_expression.as_deref(),
+        samples.len(),
+    )?;
+    for warning in &checked.warnings {
+        eprintln!(
+            "warning: {} (checked with a synthetic read and pile)",
+            warning
+        );
+    }
+
+    let summarizer = if opts.summary {
+        let summarizer = match &opts.bedfile {
+            Some(bed) => Summarizer::from_bed(&header, bed)?,
+            None => Summarizer::by_chromosome(&header),
+        };
+        Some(match (&opts.fasta, opts.trinucleotides) {
+            (Some(fasta), true) => summarizer.with_trinucleotides(CachedFaidx::new(fasta)?),
+            _ => summarizer,
+        })
+    } else {
+        None
+    };
+    let trinucleotides = match (&opts.fasta, opts.trinucleotides && !opts.summary) {
+        (Some(fasta), true) => Some(TrinucleotideCounts::new(CachedFaidx::new(fasta)?)),
+        _ => None,
+    };
+    if opts.gvcf_blocks.is_some() && opts.output_format != OutputFormat::Vcf {
+        eprintln!("--gvcf-blocks requires --output-format vcf");
+        std::process::exit(1);
+    }
+    if (opts.min_gap.is_some() || opts.bed_score) && opts.output_format != OutputFormat::Bed {
+        eprintln!("--min-gap and --bed-score require --output-format bed");
+        std::process::exit(1);
+    }
+    if opts.output_format == OutputFormat::Arrow && opts.output.as_deref().is_some_and(is_bgzf) {
+        eprintln!("--output-format arrow can not be bgzip-compressed; use a path without .gz");
+        std::process::exit(1);
+    }
+    let reference = opts.fasta.clone();
+
+    let mut out = open_output(opts.output.as_deref(), opts.threads)?;
+    // a VCF must start with its ##fileformat line and an Arrow file with its
+    // magic bytes.
+    if matches!(opts.output_format, OutputFormat::Tsv | OutputFormat::Bed) {
+        writeln!(out, "# pbr version {}", env!("CARGO_PKG_VERSION"))?;
+    }
+
+    // regions are taken from the first alignment file.
+    let bam_path = opts.bam_paths[0].clone();
     let par_granges_runner = par_granges::ParGranges::new(
-        opts.bam_path,      // pass in bam
+        bam_path,           // pass in bam
         opts.fasta,         // optional ref fasta
         opts.bedfile,       // bedfile to narrow regions
         None,               // optional bcf/vcf file to specify positions of interest
@@ -423,59 +1367,88 @@ fn main() -> Result<()> {
         basic_processor,
     );
 
-    let pile_lua = Lua::new();
-    register_pile(&pile_lua)?;
-
-    let pile_expression: Option<Function> = if let Some(expression) = opts.pile_expression {
-        Some(pile_lua.load(expression.as_str()).into_function()?)
-    } else {
-        None
-    };
+    let pile_filter = PileFilter::new(
+        opts.pile_expression.as_deref(),
+        opts.output_expression.as_deref(),
+        script.as_deref(),
+    )?;
 
     // Run the processor
     let receiver = par_granges_runner.process()?;
-    println!("#chrom\tpos0\tref_base\tdepth\ta\tc\tg\tt\tn");
     // Pull the in-order results from the receiver channel
-    receiver
+    let positions = receiver
         .into_iter()
-        .filter(|p| p.depth > 0)
+        .filter(|ps| ps.iter().any(|p| p.depth > 0))
         // filter on the pile expression
-        .filter(|p| {
-            if let Some(pile_expression) = &pile_expression {
-                let r = pile_lua.scope(|scope| {
-                    let globals = pile_lua.globals();
-                    let ud = scope.create_any_userdata_ref(p)?;
-                    globals.set("pile", ud).expect("error setting pile");
-
-                    pile_expression.call::<bool>(())
-                });
-                match r {
-                    Ok(r) => r,
-                    Err(e) => {
-                        eprintln!("Error evaluating expression: {}", e);
-                        std::process::exit(1);
-                    }
-                }
-            } else {
-                true
+        .filter(|ps| match pile_filter.keep(ps) {
+            Ok(r) => r,
+            Err(e) => {
+                eprintln!("Error evaluating expression: {}", e);
+                std::process::exit(1);
             }
-        })
-        .for_each(|p: PileupPosition| {
-            //p:PileupPosition { ref_seq: "chr2", pos: 196, ref_base: None, depth: 1, a: 1, c: 0, g: 0, t: 0, n: 0, ins: 0, del: 0, ref_skip: 0, fail: 1, near_max_depth: false }
-            println!(
-                "{chrom}\t{pos}\t{ref_base}\t{depth}\t{a}\t{c}\t{g}\t{t}\t{n}",
-                chrom = p.ref_seq,
-                pos = p.pos,
-                depth = p.depth,
-                ref_base = p.ref_base.unwrap_or('.'),
-                a = p.a,
-                c = p.c,
-                g = p.g,
-                t = p.t,
-                n = p.n
-            );
         });
 
+    if let Some(mut summarizer) = summarizer {
+        for ps in positions {
+            summarizer.add(&ps)?;
+        }
+        summarizer.write(out)?;
+        pile_filter.finalize()?;
+        errors.report();
+        return Ok(());
+    }
+    if let Some(mut trinucleotides) = trinucleotides {
+        for ps in positions {
+            trinucleotides.add(&ps)?;
+        }
+        trinucleotides.write(out)?;
+        pile_filter.finalize()?;
+        errors.report();
+        return Ok(());
+    }
+
+    let mut writer: Box<dyn PositionWriter> = match opts.output_format {
+        OutputFormat::Tsv => Box::new(
+            TsvWriter::new(out, opts.columns.clone(), &samples)
+                .with_extra_columns(opts.output_columns.clone()),
+        ),
+        OutputFormat::Vcf => Box::new(VcfWriter::new(
+            out,
+            &header,
+            &samples,
+            reference.as_ref().and_then(|f| f.to_str()),
+            opts.gvcf_blocks,
+        )),
+        OutputFormat::Bed => Box::new(BedWriter::new(
+            out,
+            opts.min_gap.unwrap_or(0),
+            opts.bed_score,
+        )),
+        OutputFormat::Arrow => Box::new(
+            ArrowWriter::new(out, &header, opts.columns.clone(), &samples)?
+                .with_extra_columns(opts.output_columns.clone()),
+        ),
+    };
+    positions.try_for_each(|ps: Vec<PbrPosition>| {
+        let formatted = match pile_filter.format(&ps) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                eprintln!("Error evaluating format: {}", e);
+                std::process::exit(1);
+            }
+        };
+        writer.write(&ps, &formatted)
+    })?;
+    writer.finish()?;
+    pile_filter.finalize()?;
+    errors.report();
+
+    // the bgzf file is closed when the writer is dropped so it can be indexed.
+    drop(writer);
+    if let Some(path) = opts.output.as_deref().filter(|p| is_bgzf(p)) {
+        tabix_index(path, opts.output_format, &opts.columns, &samples)?;
+    }
+
     Ok(())
 }
 
@@ -483,6 +1456,7 @@ fn main() -> Result<()> {
 mod tests {
 
     use super::*;
+    use crate::test_utils::{header, header_view, position, record, SEQUENCES};
     use mlua::Lua;
     use rust_htslib::bam;
     use rust_htslib::bam::pileup::Pileup;
@@ -528,10 +1502,10 @@ mod tests {
             .next()
             .expect("No alignment found in pileup");
 
-        let lua = Lua::new();
         let rf = LuaReadFilter::new(
             "return read.bq > 0 and read.distance_from_5prime == 0 and read.distance_from_3prime > 0",
-            &lua,
+            Lua::new(),
+            None,
         )?; // Example expression
 
         // Test the bq functionality using the alignment from the pileup
@@ -541,9 +1515,620 @@ mod tests {
         Ok(())
     }
 
+    /// Write the SAM records to an indexed temporary BAM with a single chr1.
+    fn write_bam(sam: &[&[u8]]) -> Result<NamedTempFile> {
+        let tmp = NamedTempFile::new()?;
+        {
+            let header = header(SEQUENCES);
+            let mut writer = bam::Writer::from_path(tmp.path(), &header, bam::Format::Bam)?;
+            for line in sam {
+                writer.write(&record(line))?;
+            }
+        }
+        bam::index::build(tmp.path(), None, bam::index::Type::Bai, 1)?;
+        Ok(tmp)
+    }
+
+    /// Evaluate the expression for each alignment in the pileup column at 0-based `pos`.
+    fn eval_at(
+        bam: &NamedTempFile,
+        pos: u32,
+        expression: &str,
+        fai: Option<CachedFaidx>,
+    ) -> Result<Vec<bool>> {
+        let mut reader = IndexedReader::from_path(bam.path())?;
+        reader.fetch(("chr1", pos as i64, pos as i64 + 1))?;
+        let rf = LuaReadFilter::new(expression, Lua::new(), fai)?;
+        rf.set_column("chr1", pos);
+        for pileup in reader.pileup() {
+            let pileup = pileup?;
+            if pileup.pos() != pos {
+                continue;
+            }
+            return Ok(pileup
+                .alignments()
+                .map(|a| rf.filter_read(&a.record(), Some(&a)))
+                .collect());
+        }
+        Ok(vec![])
+    }
+
+    #[test]
+    fn test_compile() -> Result<()> {
+        let lua = Lua::new();
+        // bare expressions and statements with a return both compile.
+        assert!(compile(&lua, "1 + 1 == 2", "expression")?.call::<bool>(())?);
+        assert!(compile(&lua, "local x = 2; return x == 2", "expression")?.call::<bool>(())?);
+
+        let err = compile(&lua, "1 +", "--read-expression").unwrap_err();
+        assert!(err.to_string().contains("--read-expression:1:"), "{}", err);
+        let err = compile(&lua, "local x = 2\nreturn x +", "expression").unwrap_err();
+        assert!(err.to_string().contains("expression:2:"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_expressions() -> Result<()> {
+        let processor = |expression: &str, read_expression: Option<&str>| BasicProcessor {
+            bamfiles: vec![],
+            expression: expression.to_string(),
+            script: false,
+            read_expression: read_expression.map(String::from),
+            max_depth: 100,
+            exclude_regions: None,
+            mate_fix: false,
+            fasta_path: None,
+            errors: Arc::new(ExpressionErrors::default()),
+            workers: Mutex::new(Vec::new()),
+        };
+        let header = header_view(SEQUENCES);
+
+        let p = processor("read.mapping_quality > 10", Some("return read.length == 4"));
+        let checked = check_expressions(&p, &header, Some("pile.depth > 0"), None, 2)?;
+        assert!(checked.warnings.is_empty(), "{:?}", checked.warnings);
+
+        // a script is compiled but none of its hooks are called.
+        let mut p = processor(
+            "function init() error('init') end
+             function read_filter(r) error('read_filter') end
+             function pile_filter(pile) error('pile_filter') end
+             function format(pile) error('format') end",
+            None,
+        );
+        p.script = true;
+        let checked = check_expressions(&p, &header, None, None, 1)?;
+        assert!(checked.warnings.is_empty(), "{:?}", checked.warnings);
+        p.expression = "function init(".to_string();
+        assert!(check_expressions(&p, &header, None, None, 1).is_err());
+
+        // a compile error is fatal.
+        let p = processor("read.mapping_quality >", None);
+        let err = check_expressions(&p, &header, None, None, 1).unwrap_err();
+        assert!(err.to_string().contains("expression:1:"), "{}", err);
+
+        // a runtime error in an expression is a warning with its line.
+        let p = processor("local x = read.mapping_quality\nreturn x.y > 1", None);
+        let warnings = check_expressions(&p, &header, None, None, 1)?.warnings;
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("expression:2:"), "{}", warnings[0]);
+
+        // the synthetic read has no tags.
+        let p = processor("read:tag('NM') < 3", None);
+        let warnings = check_expressions(&p, &header, None, None, 1)?.warnings;
+        assert_eq!(warnings.len(), 1);
+
+        let p = processor("true", None);
+        let warnings = check_expressions(&p, &header, None, Some("piles[2].depth"), 1)?.warnings;
+        assert_eq!(warnings.len(), 1);
+        assert!(
+            warnings[0].contains("--output-expression:1:"),
+            "{}",
+            warnings[0]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_expression_error_policy() -> Result<()> {
+        let read = synthetic_read();
+        let expression = "read.mapping_quality.x > 1";
+        let rf = LuaReadFilter::new(expression, Lua::new(), None)?;
+        assert!(!rf.filter_read(&read, None));
+
+        let errors = Arc::new(ExpressionErrors::new(ErrorPolicy::Keep));
+        let rf = LuaReadFilter::new(expression, Lua::new(), None)?.with_errors(errors.clone());
+        assert!(rf.filter_read(&read, None));
+        assert!(rf.filter_read(&read, None));
+        let summary = errors.summary();
+        assert_eq!(summary.len(), 1);
+        assert!(summary[0].0.contains("expression:1:"), "{}", summary[0].0);
+        assert_eq!(summary[0].1, 2);
+        Ok(())
+    }
+
+    /// Evaluate the expression for the SAM record outside of a pileup.
+    fn eval_record(sam: &[u8], expression: &str) -> mlua::Result<bool> {
+        let record = record(sam);
+        let rf = LuaReadFilter::new(expression, Lua::new(), None).unwrap();
+        rf.try_eval(&rf.filter_func, &record, None)
+    }
+
+    #[test]
+    fn test_read_tags() -> Result<()> {
+        let sam = b"tagged\t0\tchr1\t100\t30\t4M\t*\t0\t0\tACGT\t&&&&\tNM:i:2\tXT:Z:abc\tHX:H:1AE3";
+        let eval = |expression| eval_record(sam, expression);
+        for expression in [
+            "read:has_tag('NM') and not read:has_tag('XS')",
+            "read:tag('NM') == 2 and read:tag('XS', -1) == -1",
+            "read:tag_int('NM') == 2 and read:tag_int('XS') == nil and read:tag_int('XS', 0) == 0",
+            "read:tag_str('XT') == 'abc' and read:tag_str('XS', '') == ''",
+            // hex byte arrays are decoded.
+            "read:tag_str('HX') == '\\26\\227' and read:tag('HX') == '\\x1a\\xe3'",
+        ] {
+            assert!(eval(expression)?, "{}", expression);
+        }
+        for (expression, error) in [
+            ("read:tag('XS') == 1", "read has no XS tag"),
+            ("read:tag_int('XT') == 1", "tag XT is not an integer"),
+            ("read:tag_str('NM') == ''", "tag NM is not a string"),
+        ] {
+            let err = eval(expression).unwrap_err().to_string();
+            assert!(err.contains(error), "{}", err);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_flags() -> mlua::Result<()> {
+        // paired, proper pair, reverse, read1 and duplicate.
+        let sam = b"dup\t1107\tchr1\t100\t30\t4M\t*\t0\t0\tACGT\t*";
+        for expression in [
+            "read.is_duplicate and read.is_read1 and read.is_reverse and read.is_paired",
+            "not (read.is_secondary or read.is_supplementary or read.is_qcfail or read.is_unmapped or read.is_read2)",
+            "read:has_flag(flag.DUP) and read:has_flag(flag.SECONDARY + flag.DUP)",
+            "not read:has_flag(bit32.bor(flag.SECONDARY, flag.QCFAIL))",
+            "flag.UNMAP == 4 and flag.SUPPLEMENTARY == 2048",
+        ] {
+            assert!(eval_record(sam, expression)?, "{}", expression);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_arrays() -> mlua::Result<()> {
+        // base qualities are 5, 10, 20, 30 and 40.
+        let sam = b"arrays\t0\tchr1\t100\t30\t5M\t*\t0\t0\tACNTG\t&+5?I";
+        for expression in [
+            "read:base_at(0) == 'A' and read:base_at(4) == 'G'",
+            "read:base_at(5) == nil and read:base_at(-1) == nil",
+            "read:qual_at(1) == 10 and read:qual_at(4) == 40 and read:qual_at(5) == nil",
+            "#read.quals == 5 and read.quals[1] == 5 and read.quals[5] == 40",
+            "read:count_base('N') == 1 and read:count_base('a', 0, 1) == 1",
+            "read:count_base('G', 0, 4) == 0 and read:count_base('G', 2, 100) == 1",
+        ] {
+            assert!(eval_record(sam, expression)?, "{}", expression);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_cigar() -> mlua::Result<()> {
+        // a reverse read so the 5' hard clip is the trailing one.
+        let sam = b"cigar\t16\tchr1\t100\t30\t3H2S3M2I1M4D2M5H\t*\t0\t0\tACGTACGTAC\t*";
+        for expression in [
+            "read.cigar == '3H2S3M2I1M4D2M5H'",
+            "read.aligned_length == 6 and read.max_indel_length == 4 and read.reference_length == 10",
+            "read.hard_clips_5_prime == 5 and read.hard_clips_3_prime == 3",
+            "local ops = read:cigar_ops()
+             return #ops == 8 and ops[1].op == 'H' and ops[1].len == 3 and ops[4].op == 'I'",
+        ] {
+            assert!(eval_record(sam, expression)?, "{}", expression);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_alignment() -> Result<()> {
+        let bam = write_bam(&[b"test_read\t0\tchr1\t100\t30\t2M2D2M\t*\t0\t0\tACGT\t&&&&"])?;
+
+        for (pos, expression) in [
+            (
+                99,
+                "return read.is_head and not read.is_tail and read.indel == 0",
+            ),
+            (
+                100,
+                "return not read.is_del and read.indel == -2 and read.bq > 0",
+            ),
+            (
+                101,
+                "return read.is_del and not read.is_refskip and read.bq == -1",
+            ),
+            (102, "return read.is_del and read.indel == 0"),
+            (104, "return read.is_tail and not read.is_head"),
+        ] {
+            assert_eq!(
+                eval_at(&bam, pos, expression, None)?,
+                vec![true],
+                "{}",
+                expression
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_end_distances() -> Result<()> {
+        // all reads have their first aligned base at 0-based 99.
+        let bam = write_bam(&[
+            b"fwd\t0\tchr1\t100\t30\t4M\t*\t0\t0\tACGT\t*",
+            b"rev\t16\tchr1\t100\t30\t4M\t*\t0\t0\tACGT\t*",
+            b"fwd_clipped\t0\tchr1\t100\t30\t2S4M1S\t*\t0\t0\tNNACGTA\t*",
+            b"rev_clipped\t16\tchr1\t100\t30\t2S4M1S\t*\t0\t0\tNNACGTA\t*",
+        ])?;
+        // distance from the 5' and 3' ends and the same without soft clips.
+        let expression = "local expected = {
+                fwd = {0, 3, 0, 3},
+                rev = {3, 0, 3, 0},
+                fwd_clipped = {2, 4, 0, 3},
+                rev_clipped = {4, 2, 3, 0},
+            }
+            local e = expected[read.qname]
+            return read.distance_from_5prime == e[1] and read.distance_from_3prime == e[2]
+                and read.aligned_distance_from_5prime == e[3]
+                and read.aligned_distance_from_3prime == e[4]";
+        assert_eq!(eval_at(&bam, 99, expression, None)?, vec![true; 4]);
+        // the last aligned base of the forward reads.
+        let expression = "local expected = {fwd = 0, rev = 3, fwd_clipped = 1, rev_clipped = 5}
+            return read.distance_from_3prime == expected[read.qname]";
+        assert_eq!(eval_at(&bam, 102, expression, None)?, vec![true; 4]);
+
+        // there is no distance at a deletion.
+        let bam = write_bam(&[b"del\t16\tchr1\t100\t30\t2S2M1D2M\t*\t0\t0\tNNACGT\t*"])?;
+        let expression = "read.distance_from_5prime == -1 and read.distance_from_3prime == -1
+            and read.aligned_distance_from_5prime == -1 and read.aligned_distance_from_3prime == -1";
+        assert_eq!(eval_at(&bam, 101, expression, None)?, vec![true]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_mate() -> Result<()> {
+        // the mate covers 0-based 101 to 104.
+        let bam = write_bam(&[b"r1\t99\tchr1\t100\t30\t4M\t=\t102\t6\tACGT\t&&&&\tMC:Z:4M"])?;
+        let expression = "read.mate_tid == 0 and read.mate_start == 101 and read.mate_is_reverse
+            and read.is_proper_pair and read.is_first_in_template and not read.is_chimeric";
+        assert_eq!(eval_at(&bam, 99, expression, None)?, vec![true]);
+        let expression = "read.overlaps_mate_at_position";
+        assert_eq!(eval_at(&bam, 99, expression, None)?, vec![false]);
+        assert_eq!(eval_at(&bam, 102, expression, None)?, vec![true]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_base() -> Result<()> {
+        // chr1 in test_cram.fa starts with GGGCACAGCC
+        let bam = write_bam(&[b"test_read\t0\tchr1\t1\t30\t4M\t*\t0\t0\tGTGC\t&&&&"])?;
+        let fasta = format!("{}/test/test_cram.fa", env!("CARGO_MANIFEST_DIR"));
+
+        for (pos, expression) in [
+            (0, "return read.base == 'G' and read.ref_base == 'G'"),
+            (1, "return read.base == 'T' and read.ref_base == 'G'"),
+            (1, "return read.base ~= read.ref_base"),
+            (3, "return read.base == read.ref_base"),
+        ] {
+            let fai = CachedFaidx::new(&fasta)?;
+            assert_eq!(
+                eval_at(&bam, pos, expression, Some(fai))?,
+                vec![true],
+                "{}",
+                expression
+            );
+        }
+        // without a fasta, ref_base is nil
+        assert_eq!(
+            eval_at(&bam, 1, "return read.ref_base == nil", None)?,
+            vec![true]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_mismatches() -> Result<()> {
+        // reference is GGGCACAG so the read mismatches at 1 and 4.
+        let bam = write_bam(&[b"test_read\t0\tchr1\t1\t30\t8M\t*\t0\t0\tGTGCTCAG\t&&&&&&&&"])?;
+        let fasta = format!("{}/test/test_cram.fa", env!("CARGO_MANIFEST_DIR"));
+
+        for (pos, expression) in [
+            (0, "return read.mismatch_count == 2"),
+            (0, "return read:mismatches_in_window(0) == 0"),
+            (0, "return read:mismatches_in_window(1) == 1"),
+            (4, "return read:mismatches_in_window(0) == 1"),
+            (4, "return read:mismatches_in_window(3) == 2"),
+            (4, "return read:indels_in_window(10) == 0"),
+        ] {
+            let fai = CachedFaidx::new(&fasta)?;
+            assert_eq!(
+                eval_at(&bam, pos, expression, Some(fai))?,
+                vec![true],
+                "{}",
+                expression
+            );
+        }
+        // mismatches are an error without a fasta so the read fails.
+        assert_eq!(
+            eval_at(&bam, 0, "return read.mismatch_count == 0", None)?,
+            vec![false]
+        );
+
+        // the mismatches are found once for all the columns of the read.
+        let rf = LuaReadFilter::new(
+            "return read.mismatch_count == 2",
+            Lua::new(),
+            Some(CachedFaidx::new(&fasta)?),
+        )?;
+        let mut reader = IndexedReader::from_path(bam.path())?;
+        reader.fetch(("chr1", 0, 8))?;
+        for pileup in reader.pileup() {
+            let pileup = pileup?;
+            rf.set_column("chr1", pileup.pos());
+            for a in pileup.alignments() {
+                assert!(rf.filter_read(&a.record(), Some(&a)));
+            }
+        }
+        let cached = |rf: &LuaReadFilter| rf.lua.app_data_ref::<MismatchCache>().unwrap().0.len();
+        assert_eq!(cached(&rf), 1);
+        rf.clear_read_cache();
+        assert_eq!(cached(&rf), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_expression_once() -> Result<()> {
+        let bam = write_bam(&[
+            b"read1\t0\tchr1\t100\t30\t4M\t*\t0\t0\tACGT\t&&&&",
+            b"read2\t0\tchr1\t101\t5\t4M\t*\t0\t0\tACGT\t&&&&",
+        ])?;
+        let rf = LuaReadFilter::new(
+            "position_calls = (position_calls or 0) + 1; return true",
+            Lua::new(),
+            None,
+        )?
+        .with_read_expression(
+            "read_calls = (read_calls or 0) + 1; return read.mapping_quality > 10",
+        )?;
+
+        let mut reader = IndexedReader::from_path(bam.path())?;
+        reader.fetch(("chr1", 0, 1000))?;
+        let mut passed = 0;
+        for pileup in reader.pileup() {
+            let pileup = pileup?;
+            rf.set_column("chr1", pileup.pos());
+            for a in pileup.alignments() {
+                if rf.filter_read(&a.record(), Some(&a)) {
+                    passed += 1;
+                }
+            }
+        }
+        // read1 covers 4 columns, read2 fails the read expression.
+        assert_eq!(passed, 4);
+        assert_eq!(rf.lua.globals().get::<i64>("read_calls")?, 2);
+        assert_eq!(rf.lua.globals().get::<i64>("position_calls")?, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_script() -> Result<()> {
+        let bam = write_bam(&[
+            b"read1\t0\tchr1\t100\t30\t4M\t*\t0\t0\tACGT\t&&&&",
+            b"read2\t0\tchr1\t100\t5\t4M\t*\t0\t0\tACGT\t&&&&",
+        ])?;
+        let mut reader = IndexedReader::from_path(bam.path())?;
+        reader.fetch(("chr1", 99, 100))?;
+        let mut pileups = reader.pileup();
+        let pileup = pileups.next().unwrap()?;
+
+        let script = "
+            local min_mapq = 10
+            function read_filter(r)
+                return r.mapping_quality > min_mapq
+            end";
+        let rf = LuaReadFilter::from_script(script, Lua::new(), None)?;
+        let kept: Vec<bool> = pileup
+            .alignments()
+            .map(|a| rf.filter_read(&a.record(), Some(&a)))
+            .collect();
+        assert_eq!(kept, vec![true, false]);
+
+        // without read_filter, all reads are kept.
+        let rf = LuaReadFilter::from_script("function init() end", Lua::new(), None)?;
+        assert!(pileup
+            .alignments()
+            .all(|a| rf.filter_read(&a.record(), Some(&a))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_pile_script() -> mlua::Result<()> {
+        let script = "
+            function init() seen = 0 end
+            function pile_filter(pile)
+                seen = seen + 1
+                return pile.depth > 5
+            end
+            function format(pile)
+                if pile.a > 0 then return 'pos:' .. pile.pos end
+            end
+            function finalize() finalized = seen end";
+        let pf = PileFilter::new(Some("return pile.n == 0"), None, Some(script))?;
+        let mut p = position("chr1", 10, 10, None);
+        assert!(pf.keep(&[position("chr1", 10, 10, None)])?);
+        assert!(!pf.keep(&[position("chr1", 10, 3, None)])?);
+        // the pile expression fails so pile_filter is not called.
+        p.n = 1;
+        assert!(!pf.keep(std::slice::from_ref(&p))?);
+        assert_eq!(
+            pf.format(&[position("chr1", 10, 10, None)])?,
+            Formatted::Line(String::from("pos:10"))
+        );
+        (p.a, p.n) = (0, 0);
+        assert_eq!(pf.format(&[p])?, Formatted::Default);
+        pf.finalize()?;
+        assert_eq!(pf.lua.globals().get::<i64>("finalized")?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_expression() -> mlua::Result<()> {
+        let pf = PileFilter::new(
+            None,
+            Some("return {vaf = (pile.depth - pile.a) / pile.depth, a = pile.a, ok = pile.a > 2, none = nil}"),
+            None,
+        )?;
+        let p = PileupPosition {
+            depth: 4,
+            a: 3,
+            ..Default::default()
+        };
+        assert_eq!(
+            pf.format(&[p.into()])?,
+            Formatted::Columns(vec![
+                (String::from("a"), String::from("3")),
+                (String::from("ok"), String::from("true")),
+                (String::from("vaf"), String::from("0.25")),
+            ])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_strand_counts() -> Result<()> {
+        let bam = write_bam(&[
+            b"del\t0\tchr1\t99\t30\t1M2D2M\t*\t0\t0\tGAC\t&&&",
+            b"fwd\t0\tchr1\t100\t30\t4M\t*\t0\t0\tACGT\t&&&&",
+            b"rev\t16\tchr1\t100\t30\t4M\t*\t0\t0\tACGT\t&&&&",
+            b"low\t16\tchr1\t100\t5\t4M\t*\t0\t0\tTCGT\t&&&&",
+        ])?;
+        let mut reader = IndexedReader::from_path(bam.path())?;
+        let header = reader.header().to_owned();
+        reader.fetch(("chr1", 99, 100))?;
+        let rf = LuaReadFilter::new("return read.mapping_quality > 10", Lua::new(), None)?;
+        let mut pileups = reader.pileup();
+        let pileup = pileups
+            .find(|p| p.as_ref().map(|p| p.pos() == 99).unwrap_or(false))
+            .expect("pileup at 99")?;
+        let tally = ColumnTally::new(&rf, false);
+        let p = tally.finish(PileupPosition::from_pileup(pileup, &header, &tally, None));
+        assert_eq!((p.depth, p.a, p.del, p.fail), (3, 2, 1, 1));
+        assert_eq!(
+            (p.strand.base(b'A', false), p.strand.base(b'A', true)),
+            (1, 1)
+        );
+        assert_eq!(p.strand.base(b'T', true), 0);
+        assert_eq!((p.strand.depth(false), p.strand.depth(true)), (2, 1));
+        // the deletion has a mapping quality but no base quality.
+        assert_eq!(p.quality.mean_bq, Some(5.0));
+        assert_eq!(p.quality.median_bq, Some(5.0));
+        assert_eq!(p.quality.mean_mapq, Some(30.0));
+        assert_eq!(p.quality.mq0_count, 0);
+
+        let pf = PileFilter::new(
+            Some("return pile.depth_fwd == 2 and pile.a_rev == 1 and pile.t_rev == 0 and pile.mean_mapq > 20"),
+            None,
+            None,
+        )?;
+        assert!(pf.keep(&[p])?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mate_fix_strand_counts() -> Result<()> {
+        let bam = write_bam(&[
+            b"pair\t99\tchr1\t100\t30\t4M\t=\t100\t4\tACGT\t&&&&",
+            b"pair\t147\tchr1\t100\t40\t4M\t=\t100\t-4\tACGT\t&&&&",
+            b"tie\t147\tchr1\t100\t30\t4M\t=\t100\t-4\tACGT\t&&&&",
+            b"tie\t99\tchr1\t100\t30\t4M\t=\t100\t4\tACGT\t&&&&",
+        ])?;
+        let mut reader = IndexedReader::from_path(bam.path())?;
+        let header = reader.header().to_owned();
+        reader.fetch(("chr1", 99, 100))?;
+        let rf = LuaReadFilter::new("return true", Lua::new(), None)?;
+        let pileup = reader.pileup().next().expect("pileup at 99")?;
+        let tally = ColumnTally::new(&rf, true);
+        let p = tally.finish(PileupPosition::from_pileup_mate_aware(
+            pileup, &header, &tally, None,
+        ));
+        // each pair is counted once, with the mate of higher mapping quality
+        // or, if equal, the first in pair.
+        assert_eq!((p.depth, p.a), (2, 2));
+        assert_eq!(
+            (p.strand.base(b'A', false), p.strand.base(b'A', true)),
+            (1, 1)
+        );
+        assert_eq!(p.strand.depth(false) + p.strand.depth(true), p.depth);
+        assert_eq!(p.quality.mean_mapq, Some(35.0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_samples() -> Result<()> {
+        let bam = write_bam(&[b"read1\t0\tchr1\t100\t30\t4M\t*\t0\t0\tACGT\t&&&&"])?;
+        let path = bam.path().to_path_buf();
+        let samples = read_samples(&[path.clone()])?;
+        assert_eq!(samples.len(), 1);
+
+        // the same sample twice would give duplicate output columns.
+        let err = read_samples(&[path.clone(), path]).unwrap_err();
+        assert!(err.to_string().contains("the same sample name"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_samples() {
+        let merged = merge_samples(
+            "chr1",
+            vec![
+                vec![
+                    position("chr1", 3, 1, Some('A')),
+                    position("chr1", 5, 2, Some('C')),
+                ],
+                vec![
+                    position("chr1", 4, 3, Some('G')),
+                    position("chr1", 5, 4, Some('C')),
+                ],
+            ],
+        );
+        let summary: Vec<Vec<(u32, u32, Option<char>)>> = merged
+            .iter()
+            .map(|ps| ps.iter().map(|p| (p.pos, p.depth, p.ref_base)).collect())
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                vec![(3, 1, Some('A')), (3, 0, Some('A'))],
+                vec![(4, 0, Some('G')), (4, 3, Some('G'))],
+                vec![(5, 2, Some('C')), (5, 4, Some('C'))],
+            ]
+        );
+        assert_eq!(merged[1][0].ref_seq, "chr1");
+    }
+
+    #[test]
+    fn test_piles_expression() -> mlua::Result<()> {
+        let pf = PileFilter::new(
+            Some("return piles[1].depth >= 10 and piles[2].depth >= 5 and pile == piles[1]"),
+            None,
+            None,
+        )?;
+        let piles = |depths: [u32; 2]| depths.map(|depth| position("chr1", 0, depth, None));
+        assert!(pf.keep(&piles([10, 5]))?);
+        assert!(!pf.keep(&piles([10, 4]))?);
+        assert!(!pf.keep(&piles([9, 20]))?);
+        Ok(())
+    }
+
     #[test]
     fn test_pileup_position() -> mlua::Result<()> {
-        let pileup_position = PileupPosition {
+        let pileup_position = PbrPosition::from(PileupPosition {
             depth: 10,
             a: 1,
             c: 2,
@@ -556,7 +2141,7 @@ mod tests {
             ref_skip: 9,
             pos: 10,
             ..Default::default()
-        };
+        });
 
         let lua = Lua::new();
         register_pile(&lua)?;
@@ -0,0 +1,565 @@
+use crate::position::PbrPosition;
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use perbase_lib::position::pileup_position::PileupPosition;
+use rust_htslib::{bgzf, htslib, tpool::ThreadPool};
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// The default columns of the tab-delimited output.
+pub(crate) const DEFAULT_COLUMNS: &str = "chrom,pos0,ref_base,depth,a,c,g,t,n";
+
+/// A column of the tab-delimited output. These are the fields of
+/// `PileupPosition`, the per-strand counts and values derived from them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Column {
+    #[value(name = "chrom")]
+    Chrom,
+    #[value(name = "pos0")]
+    Pos0,
+    #[value(name = "ref_base")]
+    RefBase,
+    #[value(name = "depth")]
+    Depth,
+    #[value(name = "a")]
+    A,
+    #[value(name = "c")]
+    C,
+    #[value(name = "g")]
+    G,
+    #[value(name = "t")]
+    T,
+    #[value(name = "n")]
+    N,
+    #[value(name = "ins")]
+    Ins,
+    #[value(name = "del")]
+    Del,
+    #[value(name = "ref_skip")]
+    RefSkip,
+    #[value(name = "fail")]
+    Fail,
+    #[value(name = "near_max_depth")]
+    NearMaxDepth,
+    /// number of reads with the reference base; requires --fasta
+    #[value(name = "ref_count")]
+    RefCount,
+    /// number of reads with an A, C, G or T that is not the reference base; requires --fasta
+    #[value(name = "alt_count")]
+    AltCount,
+    #[value(name = "a_fwd")]
+    AFwd,
+    #[value(name = "a_rev")]
+    ARev,
+    #[value(name = "c_fwd")]
+    CFwd,
+    #[value(name = "c_rev")]
+    CRev,
+    #[value(name = "g_fwd")]
+    GFwd,
+    #[value(name = "g_rev")]
+    GRev,
+    #[value(name = "t_fwd")]
+    TFwd,
+    #[value(name = "t_rev")]
+    TRev,
+    #[value(name = "n_fwd")]
+    NFwd,
+    #[value(name = "n_rev")]
+    NRev,
+    #[value(name = "depth_fwd")]
+    DepthFwd,
+    #[value(name = "depth_rev")]
+    DepthRev,
+    /// mean base quality of the reads passing the filters
+    #[value(name = "mean_bq")]
+    MeanBq,
+    #[value(name = "median_bq")]
+    MedianBq,
+    /// mean mapping quality of the reads passing the filters
+    #[value(name = "mean_mapq")]
+    MeanMapq,
+    /// number of reads passing the filters with a mapping quality of 0
+    #[value(name = "mq0_count")]
+    Mq0Count,
+}
+
+/// Number of reads supporting the reference base or None if it is not known.
+pub(crate) fn ref_count(p: &PileupPosition) -> Option<u32> {
+    match p.ref_base?.to_ascii_uppercase() {
+        'A' => Some(p.a),
+        'C' => Some(p.c),
+        'G' => Some(p.g),
+        'T' => Some(p.t),
+        _ => Some(0),
+    }
+}
+
+/// Number of reads with a base (not N) that differs from the reference.
+pub(crate) fn alt_count(p: &PileupPosition) -> Option<u32> {
+    ref_count(p).map(|r| p.a + p.c + p.g + p.t - r)
+}
+
+impl Column {
+    /// Whether the column is the same for all samples and so is only written once.
+    pub(crate) fn shared(self) -> bool {
+        matches!(self, Column::Chrom | Column::Pos0 | Column::RefBase)
+    }
+
+    /// The name of the column as given to --columns.
+    pub(crate) fn name(self) -> String {
+        self.to_possible_value()
+            .expect("no skipped columns")
+            .get_name()
+            .to_string()
+    }
+
+    /// The value of a count column or None for other columns and unknown counts.
+    pub(crate) fn count(self, p: &PbrPosition) -> Option<u32> {
+        match self {
+            Column::Pos0 => Some(p.pos),
+            Column::Depth => Some(p.depth),
+            Column::A => Some(p.a),
+            Column::C => Some(p.c),
+            Column::G => Some(p.g),
+            Column::T => Some(p.t),
+            Column::N => Some(p.n),
+            Column::Ins => Some(p.ins),
+            Column::Del => Some(p.del),
+            Column::RefSkip => Some(p.ref_skip),
+            Column::Fail => Some(p.fail),
+            Column::RefCount => ref_count(p),
+            Column::AltCount => alt_count(p),
+            Column::AFwd => Some(p.strand.base(b'A', false)),
+            Column::ARev => Some(p.strand.base(b'A', true)),
+            Column::CFwd => Some(p.strand.base(b'C', false)),
+            Column::CRev => Some(p.strand.base(b'C', true)),
+            Column::GFwd => Some(p.strand.base(b'G', false)),
+            Column::GRev => Some(p.strand.base(b'G', true)),
+            Column::TFwd => Some(p.strand.base(b'T', false)),
+            Column::TRev => Some(p.strand.base(b'T', true)),
+            Column::NFwd => Some(p.strand.base(b'N', false)),
+            Column::NRev => Some(p.strand.base(b'N', true)),
+            Column::DepthFwd => Some(p.strand.depth(false)),
+            Column::DepthRev => Some(p.strand.depth(true)),
+            Column::Mq0Count => Some(p.quality.mq0_count),
+            _ => None,
+        }
+    }
+
+    /// The value of a column that is a (possibly unknown) mean or median.
+    pub(crate) fn value(self, p: &PbrPosition) -> Option<f64> {
+        match self {
+            Column::MeanBq => p.quality.mean_bq,
+            Column::MedianBq => p.quality.median_bq,
+            Column::MeanMapq => p.quality.mean_mapq,
+            _ => None,
+        }
+    }
+
+    fn write<W: Write>(self, out: &mut W, p: &PbrPosition) -> io::Result<()> {
+        match self {
+            Column::Chrom => write!(out, "{}", p.ref_seq),
+            Column::RefBase => write!(out, "{}", p.ref_base.unwrap_or('.')),
+            Column::NearMaxDepth => write!(out, "{}", p.near_max_depth),
+            Column::MeanBq | Column::MedianBq | Column::MeanMapq => match self.value(p) {
+                Some(value) => write!(out, "{:.2}", value),
+                None => write!(out, "."),
+            },
+            _ => match self.count(p) {
+                Some(count) => write!(out, "{}", count),
+                None => write!(out, "."),
+            },
+        }
+    }
+}
+
+/// The format of the per-position output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum OutputFormat {
+    /// tab-delimited with the --columns and any extra columns
+    #[default]
+    Tsv,
+    /// VCF with the depth and base counts of each sample
+    Vcf,
+    /// BED of merged intervals of passing positions
+    Bed,
+    /// Arrow IPC file with the --columns as typed columns
+    Arrow,
+}
+
+/// Whether the output at `path` is bgzip-compressed.
+pub(crate) fn is_bgzf(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "gz")
+}
+
+/// Open the output: stdout if there is no path, otherwise the file, with BGZF
+/// compression (using `threads`) if the path ends with .gz.
+pub(crate) fn open_output(path: Option<&Path>, threads: usize) -> Result<Box<dyn Write>> {
+    let Some(path) = path else {
+        return Ok(Box::new(BufWriter::new(io::stdout())));
+    };
+    // create the file here as the bgzf writer does not report errors opening it.
+    let file = File::create(path).with_context(|| format!("error creating {}", path.display()))?;
+    if !is_bgzf(path) {
+        return Ok(Box::new(BufWriter::new(file)));
+    }
+    drop(file);
+    let mut writer = bgzf::Writer::from_path(path)?;
+    writer.set_thread_pool(&ThreadPool::new(threads as u32)?)?;
+    Ok(Box::new(BufWriter::new(writer)))
+}
+
+/// presets from htslib's tbx.h
+const TBX_VCF: i32 = 2;
+const TBX_UCSC: i32 = 0x10000;
+
+/// Write a tabix index for the bgzipped output at `path`. Tab-delimited
+/// output is only indexed when its first columns, as laid out for the
+/// samples, are chrom and pos0.
+pub(crate) fn tabix_index(
+    path: &Path,
+    format: OutputFormat,
+    columns: &[Column],
+    samples: &[String],
+) -> Result<()> {
+    // sequence, begin and end columns (1-based); TBX_UCSC means 0-based starts.
+    let (preset, sc, bc, ec) = match format {
+        OutputFormat::Arrow => return Err(anyhow!("arrow output can not be indexed")),
+        OutputFormat::Vcf => (TBX_VCF, 1, 2, 0),
+        OutputFormat::Bed => (TBX_UCSC, 1, 2, 3),
+        OutputFormat::Tsv => {
+            let (written, _) = sample_columns(columns, samples);
+            if !written.starts_with(&[(Column::Chrom, 0), (Column::Pos0, 0)]) {
+                eprintln!(
+                    "not indexing {} as its first columns are not chrom and pos0",
+                    path.display()
+                );
+                return Ok(());
+            }
+            (TBX_UCSC, 1, 2, 2)
+        }
+    };
+    let conf = htslib::tbx_conf_t {
+        preset,
+        sc,
+        bc,
+        ec,
+        meta_char: b'#' as i32,
+        line_skip: 0,
+    };
+    let cpath = CString::new(path.to_str().context("output path is not valid UTF-8")?)?;
+    if unsafe { htslib::tbx_index_build(cpath.as_ptr(), 0, &conf) } < 0 {
+        return Err(anyhow!("error building tabix index for {}", path.display()));
+    }
+    Ok(())
+}
+
+/// Writes the positions (one per sample) that pass the filters.
+pub(crate) trait PositionWriter {
+    /// Write a position. `formatted` is the result of the output expression.
+    fn write(&mut self, ps: &[PbrPosition], formatted: &Formatted) -> io::Result<()>;
+    /// Write anything that is pending and flush.
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+/// How a position is written as returned by the script's `format(pile)` or
+/// the --output-expression.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Formatted {
+    /// the default row.
+    Default,
+    /// a line that is written instead of the default row.
+    Line(String),
+    /// named values that are appended to the default row, sorted by name.
+    Columns(Vec<(String, String)>),
+}
+
+/// Writes positions as tab-delimited rows. The header is written with the
+/// first row.
+pub(crate) struct TsvWriter<W: Write> {
+    out: W,
+    /// each output column and the index of the sample that it is taken from.
+    columns: Vec<(Column, usize)>,
+    names: Vec<String>,
+    /// names of the extra columns that are written after the columns.
+    extra_columns: Vec<String>,
+    header_written: bool,
+}
+
+/// Each output column with the index of the sample that it is taken from and
+/// its name. With a single sample, the columns are in the order given. With
+/// multiple samples, the shared columns are first followed by a group of the
+/// other columns for each sample, prefixed by the sample name.
+pub(crate) fn sample_columns(
+    columns: &[Column],
+    samples: &[String],
+) -> (Vec<(Column, usize)>, Vec<String>) {
+    if samples.len() <= 1 {
+        return columns.iter().map(|c| ((*c, 0), c.name())).unzip();
+    }
+    let shared = columns
+        .iter()
+        .filter(|c| c.shared())
+        .map(|c| ((*c, 0), c.name()));
+    let per_sample = samples.iter().enumerate().flat_map(|(i, sample)| {
+        columns
+            .iter()
+            .filter(|c| !c.shared())
+            .map(move |c| ((*c, i), format!("{}_{}", sample, c.name())))
+    });
+    shared.chain(per_sample).unzip()
+}
+
+impl<W: Write> TsvWriter<W> {
+    pub(crate) fn new(out: W, columns: Vec<Column>, samples: &[String]) -> Self {
+        let (columns, names) = sample_columns(&columns, samples);
+        TsvWriter {
+            out,
+            columns,
+            names,
+            extra_columns: vec![],
+            header_written: false,
+        }
+    }
+
+    /// Add the extra columns (--output-columns) of the output expression. A
+    /// row without a value for one of these has `.` in its place.
+    pub(crate) fn with_extra_columns(mut self, extra_columns: Vec<String>) -> Self {
+        self.extra_columns = extra_columns;
+        self
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        if self.header_written {
+            return Ok(());
+        }
+        let names = self.names.iter().chain(self.extra_columns.iter());
+        for (i, name) in names.enumerate() {
+            write!(self.out, "{}{}", if i == 0 { "#" } else { "\t" }, name)?;
+        }
+        writeln!(self.out)?;
+        self.header_written = true;
+        Ok(())
+    }
+}
+
+/// Check that each extra value is one of the `extra_columns` so that no value
+/// is dropped.
+pub(crate) fn check_extra(extra_columns: &[String], extra: &[(String, String)]) -> io::Result<()> {
+    match extra.iter().find(|(name, _)| !extra_columns.contains(name)) {
+        Some((name, _)) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "the output expression returned {} which is not in --output-columns",
+                name
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+impl<W: Write> PositionWriter for TsvWriter<W> {
+    /// Write the row for the position in each sample.
+    fn write(&mut self, ps: &[PbrPosition], formatted: &Formatted) -> io::Result<()> {
+        self.write_header()?;
+        let extra: &[(String, String)] = match formatted {
+            Formatted::Line(line) => return writeln!(self.out, "{}", line),
+            Formatted::Columns(extra) => extra.as_slice(),
+            Formatted::Default => &[],
+        };
+        check_extra(&self.extra_columns, extra)?;
+        for (i, (column, sample)) in self.columns.iter().enumerate() {
+            if i > 0 {
+                write!(self.out, "\t")?;
+            }
+            column.write(&mut self.out, &ps[*sample])?;
+        }
+        for name in &self.extra_columns {
+            let value = extra
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.as_str())
+                .unwrap_or(".");
+            write!(self.out, "\t{}", value)?;
+        }
+        writeln!(self.out)
+    }
+
+    /// Write the header if there were no positions and flush.
+    fn finish(&mut self) -> io::Result<()> {
+        self.write_header()?;
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::position;
+    use std::io::Read;
+
+    fn default_columns() -> Vec<Column> {
+        DEFAULT_COLUMNS
+            .split(',')
+            .map(|c| Column::from_str(c, false).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_tsv_extra_columns() -> io::Result<()> {
+        let mut out = vec![];
+        let extra_columns = vec![String::from("strand"), String::from("vaf")];
+        let mut w =
+            TsvWriter::new(&mut out, default_columns(), &[]).with_extra_columns(extra_columns);
+        let columns = |vaf: &str| {
+            Formatted::Columns(vec![
+                (String::from("strand"), String::from("+")),
+                (String::from("vaf"), String::from(vaf)),
+            ])
+        };
+        // the header does not depend on the first row.
+        w.write(&[position("chr1", 1, 3, None)], &Formatted::Default)?;
+        w.write(&[position("chr1", 2, 4, None)], &columns("0.5"))?;
+        w.write(
+            &[position("chr1", 3, 5, None)],
+            &Formatted::Columns(vec![(String::from("vaf"), String::from("1"))]),
+        )?;
+        w.write(
+            &[position("chr1", 4, 5, None)],
+            &Formatted::Line(String::from("custom")),
+        )?;
+        w.finish()?;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "#chrom\tpos0\tref_base\tdepth\ta\tc\tg\tt\tn\tstrand\tvaf\n\
+             chr1\t1\t.\t3\t3\t0\t0\t0\t0\t.\t.\n\
+             chr1\t2\t.\t4\t4\t0\t0\t0\t0\t+\t0.5\n\
+             chr1\t3\t.\t5\t5\t0\t0\t0\t0\t.\t1\n\
+             custom\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tsv_unknown_extra_column() {
+        let mut out = vec![];
+        let mut w = TsvWriter::new(&mut out, default_columns(), &[])
+            .with_extra_columns(vec![String::from("vaf")]);
+        let extra = Formatted::Columns(vec![(String::from("other"), String::from("x"))]);
+        let err = w
+            .write(&[position("chr1", 3, 5, None)], &extra)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("other"), "{}", err);
+    }
+
+    #[test]
+    fn test_tsv_header_without_positions() -> io::Result<()> {
+        let mut out = vec![];
+        TsvWriter::new(&mut out, default_columns(), &[]).finish()?;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "#chrom\tpos0\tref_base\tdepth\ta\tc\tg\tt\tn\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tsv_columns() -> io::Result<()> {
+        let columns = vec![
+            Column::Pos0,
+            Column::Chrom,
+            Column::Del,
+            Column::RefCount,
+            Column::AltCount,
+            Column::NearMaxDepth,
+        ];
+        let mut out = vec![];
+        let mut w = TsvWriter::new(&mut out, columns, &[String::from("s1")]);
+        let mut p: PbrPosition = PileupPosition {
+            ref_seq: "chr2".into(),
+            pos: 7,
+            depth: 10,
+            a: 1,
+            c: 6,
+            t: 2,
+            n: 1,
+            del: 3,
+            ..Default::default()
+        }
+        .into();
+        w.write(std::slice::from_ref(&p), &Formatted::Default)?;
+        p.ref_base = Some('c');
+        w.write(&[p], &Formatted::Default)?;
+        w.finish()?;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "#pos0\tchrom\tdel\tref_count\talt_count\tnear_max_depth\n\
+             7\tchr2\t3\t.\t.\tfalse\n\
+             7\tchr2\t3\t6\t3\tfalse\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tsv_samples() -> io::Result<()> {
+        let mut out = vec![];
+        let samples = [String::from("tumor"), String::from("normal")];
+        let columns = vec![Column::Depth, Column::Chrom, Column::A, Column::Pos0];
+        let mut w = TsvWriter::new(&mut out, columns, &samples);
+        w.write(
+            &[position("chr1", 5, 10, None), position("chr1", 5, 0, None)],
+            &Formatted::Default,
+        )?;
+        w.finish()?;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "#chrom\tpos0\ttumor_depth\ttumor_a\tnormal_depth\tnormal_a\n\
+             chr1\t5\t10\t10\t0\t0\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_bgzf_output() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out.tsv.gz");
+        let mut w = TsvWriter::new(open_output(Some(&path), 2)?, default_columns(), &[]);
+        for pos in [3, 4, 9] {
+            w.write(&[position("chr1", pos, 2, None)], &Formatted::Default)?;
+        }
+        w.finish()?;
+        drop(w);
+        tabix_index(&path, OutputFormat::Tsv, &default_columns(), &[])?;
+        assert!(dir.path().join("out.tsv.gz.tbi").exists());
+
+        let mut text = String::new();
+        rust_htslib::bgzf::Reader::from_path(&path)?.read_to_string(&mut text)?;
+        assert_eq!(text.lines().count(), 4);
+        assert!(text.ends_with("chr1\t9\t.\t2\t2\t0\t0\t0\t0\n"));
+
+        // without chrom and pos0 as the first columns there is no index.
+        let path = dir.path().join("depth.tsv.gz");
+        let columns = vec![Column::Depth, Column::Chrom, Column::Pos0];
+        TsvWriter::new(open_output(Some(&path), 1)?, columns.clone(), &[]).finish()?;
+        tabix_index(&path, OutputFormat::Tsv, &columns, &[])?;
+        assert!(!dir.path().join("depth.tsv.gz.tbi").exists());
+
+        // with multiple samples, chrom and pos0 are written first.
+        let path = dir.path().join("pair.tsv.gz");
+        let samples = [String::from("tumor"), String::from("normal")];
+        let mut w = TsvWriter::new(open_output(Some(&path), 1)?, columns.clone(), &samples);
+        w.write(
+            &[position("chr1", 3, 2, None), position("chr1", 3, 1, None)],
+            &Formatted::Default,
+        )?;
+        w.finish()?;
+        drop(w);
+        tabix_index(&path, OutputFormat::Tsv, &columns, &samples)?;
+        assert!(dir.path().join("pair.tsv.gz.tbi").exists());
+        Ok(())
+    }
+}
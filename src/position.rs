@@ -0,0 +1,251 @@
+use perbase_lib::{position::pileup_position::PileupPosition, read_filter::ReadFilter};
+use rust_htslib::bam::{pileup::Alignment, record::Record};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+
+/// Index of the depth in the per-strand counts, after A, C, G, T and N.
+const DEPTH: usize = 5;
+
+/// Reads passing the filters at a position on the forward and reverse strand.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct StrandCounts {
+    /// A, C, G, T, N and depth (including deletions) for each strand.
+    counts: [[u32; 6]; 2],
+}
+
+/// Index of the base in the per-strand counts. Anything but ACGT is an N.
+fn base_index(base: u8) -> usize {
+    match base.to_ascii_uppercase() {
+        b'A' => 0,
+        b'C' => 1,
+        b'G' => 2,
+        b'T' => 3,
+        _ => 4,
+    }
+}
+
+impl StrandCounts {
+    /// Count of `base` on the reverse strand if `reverse` is true, otherwise
+    /// on the forward strand.
+    pub(crate) fn base(&self, base: u8, reverse: bool) -> u32 {
+        self.counts[reverse as usize][base_index(base)]
+    }
+
+    pub(crate) fn depth(&self, reverse: bool) -> u32 {
+        self.counts[reverse as usize][DEPTH]
+    }
+}
+
+/// Base and mapping quality of the reads passing the filters at a position.
+/// These are None if no read passed.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct QualitySummary {
+    pub(crate) mean_bq: Option<f64>,
+    pub(crate) median_bq: Option<f64>,
+    pub(crate) mean_mapq: Option<f64>,
+    pub(crate) mq0_count: u32,
+}
+
+impl QualitySummary {
+    /// Summarize the base qualities of the reads with a base at the position
+    /// and the mapping qualities of all reads (including deletions).
+    fn new(quals: &mut [u8], mapqs: &[u8]) -> Self {
+        let mean = |v: &[u8]| {
+            (!v.is_empty()).then(|| v.iter().map(|q| *q as f64).sum::<f64>() / v.len() as f64)
+        };
+        quals.sort_unstable();
+        let median_bq = match quals.len() {
+            0 => None,
+            n if n % 2 == 1 => Some(quals[n / 2] as f64),
+            n => Some((quals[n / 2 - 1] as f64 + quals[n / 2] as f64) / 2.0),
+        };
+        QualitySummary {
+            mean_bq: mean(quals),
+            median_bq,
+            mean_mapq: mean(mapqs),
+            mq0_count: mapqs.iter().filter(|q| **q == 0).count() as u32,
+        }
+    }
+}
+
+/// A position as output by pbr: the perbase counts plus pbr-specific values.
+/// This dereferences to the `PileupPosition`. Nothing serializes it, it only
+/// derives `Serialize` as perbase's `RegionProcessor` requires that of the
+/// positions sent from the worker threads.
+#[derive(Default, Serialize)]
+pub(crate) struct PbrPosition {
+    pub(crate) pileup: PileupPosition,
+    #[serde(skip)]
+    pub(crate) strand: StrandCounts,
+    #[serde(skip)]
+    pub(crate) quality: QualitySummary,
+}
+
+impl From<PileupPosition> for PbrPosition {
+    fn from(pileup: PileupPosition) -> Self {
+        PbrPosition {
+            pileup,
+            ..Default::default()
+        }
+    }
+}
+
+impl Deref for PbrPosition {
+    type Target = PileupPosition;
+    fn deref(&self) -> &PileupPosition {
+        &self.pileup
+    }
+}
+
+impl DerefMut for PbrPosition {
+    fn deref_mut(&mut self) -> &mut PileupPosition {
+        &mut self.pileup
+    }
+}
+
+/// What is tallied for a read passing the filter at a column.
+#[derive(Clone, Copy)]
+struct Observation {
+    reverse: bool,
+    /// the base and its quality, None for a deletion or reference skip.
+    base: Option<(u8, u8)>,
+    is_refskip: bool,
+    mapq: u8,
+    first_in_pair: bool,
+}
+
+impl Observation {
+    fn new(read: &Record, alignment: &Alignment) -> Self {
+        Observation {
+            reverse: read.is_reverse(),
+            base: alignment
+                .qpos()
+                .map(|qpos| (read.seq()[qpos], read.qual()[qpos])),
+            is_refskip: alignment.is_refskip(),
+            mapq: read.mapq(),
+            first_in_pair: read.is_first_in_template(),
+        }
+    }
+
+    /// Whether this replaces the `counted` mate as the one that is counted
+    /// with --mate-fix. This follows perbase's choice of the mate with the
+    /// higher mapping quality or, if equal, the first in pair.
+    fn replaces(&self, counted: &Observation) -> bool {
+        match self.mapq.cmp(&counted.mapq) {
+            Ordering::Greater => true,
+            Ordering::Less => false,
+            Ordering::Equal => self.first_in_pair && !counted.first_in_pair,
+        }
+    }
+}
+
+/// What is tallied from the reads passing the filter at a column.
+#[derive(Default)]
+struct Tally {
+    strand: StrandCounts,
+    quals: Vec<u8>,
+    mapqs: Vec<u8>,
+}
+
+impl Tally {
+    fn add(&mut self, o: &Observation) {
+        // perbase does not include reference skips in the depth.
+        if o.is_refskip {
+            return;
+        }
+        let counts = &mut self.strand.counts[o.reverse as usize];
+        if let Some((base, qual)) = o.base {
+            counts[base_index(base)] += 1;
+            self.quals.push(qual);
+        }
+        counts[DEPTH] += 1;
+        self.mapqs.push(o.mapq);
+    }
+}
+
+/// Wraps the read filter used for a single pileup column to tally the strand
+/// and qualities of the reads that pass it, as perbase only reports counts.
+pub(crate) struct ColumnTally<'a, F: ReadFilter> {
+    filter: &'a F,
+    tally: RefCell<Tally>,
+    /// with --mate-fix, the mate that is counted for each read name so that
+    /// overlapping mates are counted once, as in perbase.
+    mates: Option<RefCell<HashMap<Vec<u8>, Observation>>>,
+}
+
+impl<'a, F: ReadFilter> ColumnTally<'a, F> {
+    pub(crate) fn new(filter: &'a F, mate_fix: bool) -> Self {
+        ColumnTally {
+            filter,
+            tally: RefCell::new(Tally::default()),
+            mates: mate_fix.then(|| RefCell::new(HashMap::new())),
+        }
+    }
+
+    /// Add the tallies to the position that was built with this filter.
+    pub(crate) fn finish(self, pileup: PileupPosition) -> PbrPosition {
+        let mut tally = self.tally.into_inner();
+        for o in self
+            .mates
+            .into_iter()
+            .flat_map(|m| m.into_inner().into_values())
+        {
+            tally.add(&o);
+        }
+        PbrPosition {
+            pileup,
+            strand: tally.strand,
+            quality: QualitySummary::new(&mut tally.quals, &tally.mapqs),
+        }
+    }
+
+    fn tally(&self, read: &Record, alignment: &Alignment) {
+        let o = Observation::new(read, alignment);
+        let Some(mates) = &self.mates else {
+            self.tally.borrow_mut().add(&o);
+            return;
+        };
+        let mut mates = mates.borrow_mut();
+        match mates.get_mut(read.qname()) {
+            Some(counted) if o.replaces(counted) => *counted = o,
+            Some(_) => {}
+            None => {
+                mates.insert(read.qname().to_vec(), o);
+            }
+        }
+    }
+}
+
+impl<F: ReadFilter> ReadFilter for ColumnTally<'_, F> {
+    #[inline]
+    fn filter_read(&self, read: &Record, alignment: Option<&Alignment>) -> bool {
+        let keep = self.filter.filter_read(read, alignment);
+        if let (true, Some(alignment)) = (keep, alignment) {
+            self.tally(read, alignment);
+        }
+        keep
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quality_summary() {
+        let q = QualitySummary::new(&mut [30, 10, 20, 40], &[60, 0, 20]);
+        assert_eq!(q.mean_bq, Some(25.0));
+        assert_eq!(q.median_bq, Some(25.0));
+        assert_eq!(q.mean_mapq, Some(80.0 / 3.0));
+        assert_eq!(q.mq0_count, 1);
+
+        let q = QualitySummary::new(&mut [30, 10, 20], &[0]);
+        assert_eq!(q.median_bq, Some(20.0));
+        assert_eq!((q.mean_mapq, q.mq0_count), (Some(0.0), 1));
+
+        assert_eq!(QualitySummary::new(&mut [], &[]), QualitySummary::default());
+    }
+}
@@ -1,19 +1,73 @@
 use anyhow::{anyhow, Context, Result};
 use bio::io::bed;
-use rust_htslib::bam::{pileup::Pileup, HeaderView};
+use rust_htslib::bam::{self, pileup::Pileup, HeaderView};
 use rust_lapper::{Interval, Lapper};
 
 use std::collections::HashSet;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::cached_faidx::CachedFaidx;
+use crate::errors::ExpressionErrors;
+use crate::LuaReadFilter;
 
 pub(crate) struct BasicProcessor {
-    // An indexed bamfile to query for the region we were passed
-    pub(crate) bamfile: PathBuf,
+    // Indexed bamfiles (one per sample) to query for the region we were passed
+    pub(crate) bamfiles: Vec<PathBuf>,
     pub(crate) expression: String,
+    // whether `expression` is a script defining `read_filter(read)`
+    pub(crate) script: bool,
+    pub(crate) read_expression: Option<String>,
     pub(crate) max_depth: u32,
     pub(crate) exclude_regions: Option<PathBuf>,
     pub(crate) mate_fix: bool,
     pub(crate) fasta_path: Option<PathBuf>,
+    // Errors raised by the read expressions in all workers and what to do with the read.
+    pub(crate) errors: Arc<ExpressionErrors>,
+    // Idle per-thread state (lua, readers, ...) that is reused across regions.
+    pub(crate) workers: Mutex<Vec<Worker>>,
+}
+
+/// State that is expensive to set up and so is created once per worker thread
+/// and reused for each region that the thread processes.
+pub(crate) struct Worker {
+    /// one reader per sample; all samples are filtered with the same `rf`.
+    pub(crate) readers: Vec<bam::IndexedReader>,
+    pub(crate) rf: LuaReadFilter,
+    pub(crate) exclude_intervals: Option<Vec<Lapper<u32, ()>>>,
+}
+
+impl Worker {
+    pub(crate) fn new(processor: &BasicProcessor) -> Self {
+        let mut readers: Vec<_> = processor
+            .bamfiles
+            .iter()
+            .map(|path| bam::IndexedReader::from_path(path).expect("Indexed reader"))
+            .collect();
+        let fai = if let Some(fasta) = &processor.fasta_path {
+            for reader in readers.iter_mut() {
+                reader.set_reference(fasta).expect("reference");
+            }
+            Some(CachedFaidx::new(fasta).expect("error reading fasta"))
+        } else {
+            None
+        };
+
+        // the expressions are checked in main before any worker is created.
+        let rf = LuaReadFilter::from_processor(processor, fai)
+            .unwrap_or_else(|e| panic!("error creating lua read filter: {}", e));
+
+        let exclude_intervals = processor.exclude_regions.as_ref().map(|regions_bed| {
+            BasicProcessor::bed_to_intervals(readers[0].header(), regions_bed, true)
+                .expect("BED file")
+        });
+
+        Worker {
+            readers,
+            rf,
+            exclude_intervals,
+        }
+    }
 }
 
 impl BasicProcessor {
@@ -0,0 +1,225 @@
+use rust_htslib::bam::record::{Aux, Cigar, CigarString, Record};
+
+/// Reference positions (0-based) where an aligned base of the read differs
+/// from the reference. `reference` holds the reference sequence starting at
+/// `ref_start`. Ns in either the read or the reference are not mismatches.
+pub(crate) fn mismatch_positions(record: &Record, reference: &[u8], ref_start: i64) -> Vec<i64> {
+    let seq = record.seq();
+    let mut mismatches = vec![];
+    let mut rpos = record.pos();
+    let mut qpos = 0usize;
+    for op in record.cigar().iter() {
+        match op {
+            Cigar::Match(l) | Cigar::Equal(l) | Cigar::Diff(l) => {
+                for i in 0..*l as usize {
+                    let r = rpos + i as i64 - ref_start;
+                    if r < 0 || r as usize >= reference.len() || qpos + i >= seq.len() {
+                        continue;
+                    }
+                    let ref_base = reference[r as usize].to_ascii_uppercase();
+                    let base = seq[qpos + i];
+                    if base != ref_base && base != b'=' && base != b'N' && ref_base != b'N' {
+                        mismatches.push(rpos + i as i64);
+                    }
+                }
+                qpos += *l as usize;
+                rpos += *l as i64;
+            }
+            Cigar::Ins(l) | Cigar::SoftClip(l) => qpos += *l as usize,
+            Cigar::Del(l) | Cigar::RefSkip(l) => rpos += *l as i64,
+            Cigar::HardClip(_) | Cigar::Pad(_) => {}
+        }
+    }
+    mismatches
+}
+
+/// Number of insertions and deletions in the read that are within `window`
+/// reference bases of `pos`. Insertions are placed at the reference position
+/// following them and deletions count if any deleted base is in the window.
+pub(crate) fn indels_in_window(record: &Record, pos: i64, window: i64) -> usize {
+    let mut count = 0;
+    let mut rpos = record.pos();
+    for op in record.cigar().iter() {
+        match op {
+            Cigar::Ins(_) => {
+                if (rpos - pos).abs() <= window {
+                    count += 1;
+                }
+            }
+            Cigar::Del(l) => {
+                if rpos <= pos + window && rpos + *l as i64 > pos - window {
+                    count += 1;
+                }
+                rpos += *l as i64;
+            }
+            Cigar::Match(l) | Cigar::Equal(l) | Cigar::Diff(l) | Cigar::RefSkip(l) => {
+                rpos += *l as i64
+            }
+            Cigar::SoftClip(_) | Cigar::HardClip(_) | Cigar::Pad(_) => {}
+        }
+    }
+    count
+}
+
+/// Number of read bases aligned to the reference (M, = and X operations).
+pub(crate) fn aligned_length(record: &Record) -> u32 {
+    record
+        .cigar()
+        .iter()
+        .map(|op| match op {
+            Cigar::Match(l) | Cigar::Equal(l) | Cigar::Diff(l) => *l,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Length of the longest insertion or deletion in the read, 0 if it has none.
+pub(crate) fn max_indel_length(record: &Record) -> u32 {
+    record
+        .cigar()
+        .iter()
+        .filter_map(|op| match op {
+            Cigar::Ins(l) | Cigar::Del(l) => Some(*l),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Distances from the query position `qpos` to the 5' and 3' ends of the read,
+/// 0 at the end bases. With `aligned`, soft-clipped bases are not counted so
+/// these are the distances to the first and last aligned bases.
+pub(crate) fn end_distances(record: &Record, qpos: usize, aligned: bool) -> (i64, i64) {
+    let mut left = qpos as i64;
+    let mut right = record.seq_len() as i64 - 1 - qpos as i64;
+    if aligned {
+        let cigar = record.cigar();
+        left -= cigar.leading_softclips();
+        right -= cigar.trailing_softclips();
+    }
+    if record.is_reverse() {
+        (right, left)
+    } else {
+        (left, right)
+    }
+}
+
+/// Proportion of Ns in the `n_bases` (or all bases if the read is shorter) at
+/// the 5' or 3' end of the read; 0 if there are no bases.
+pub(crate) fn n_proportion(record: &Record, n_bases: usize, five_prime: bool) -> f64 {
+    let seq = record.seq();
+    let n = n_bases.min(seq.len());
+    if n == 0 {
+        return 0.0;
+    }
+    // the 5' end is at the start of the stored sequence of forward reads.
+    let range = if five_prime != record.is_reverse() {
+        0..n
+    } else {
+        seq.len() - n..seq.len()
+    };
+    range.filter(|i| seq[*i] == b'N').count() as f64 / n as f64
+}
+
+/// Whether the mate is mapped to another chromosome.
+pub(crate) fn is_chimeric(record: &Record) -> bool {
+    record.is_paired()
+        && !record.is_unmapped()
+        && !record.is_mate_unmapped()
+        && record.tid() != record.mtid()
+}
+
+/// The (exclusive) reference end of the mate from its CIGAR in the MC tag or,
+/// without the tag, estimated from the insert size, assuming that a mate
+/// starting before the read covers as many reference bases as the read. None if
+/// the mate is unmapped or on another chromosome.
+pub(crate) fn mate_end(record: &Record) -> Option<i64> {
+    if !record.is_paired() || record.is_mate_unmapped() || record.tid() != record.mtid() {
+        return None;
+    }
+    if let Ok(Aux::String(mc)) = record.aux(b"MC") {
+        if let Ok(cigar) = CigarString::try_from(mc) {
+            return Some(cigar.into_view(record.mpos()).end_pos());
+        }
+    }
+    if record.pos() <= record.mpos() && record.insert_size() != 0 {
+        Some(record.pos() + record.insert_size().abs())
+    } else {
+        Some(record.mpos() + record.cigar().end_pos() - record.pos())
+    }
+}
+
+/// Whether the mate also covers the reference position `pos`.
+pub(crate) fn overlaps_mate_at(record: &Record, pos: i64) -> bool {
+    mate_end(record).is_some_and(|end| record.mpos() <= pos && pos < end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::record;
+
+    #[test]
+    fn test_mismatch_positions() {
+        // reference at 0-based 10..20 and a read starting at 11 with a soft-clip,
+        // an insertion and a deletion.
+        let reference = b"ACGTACGTAC";
+        let r = record(b"r\t0\tchr1\t12\t60\t1S2M1I2M2D2M\t*\t0\t0\tTCTAGANC\t*");
+        // aligned: 11:C=C 12:T!=G 13:G!=T 14:A=A, deletion 15-16, 17:N 18:C!=A
+        assert_eq!(mismatch_positions(&r, reference, 10), vec![12, 13, 18]);
+        // positions outside of the reference slice are ignored.
+        assert_eq!(mismatch_positions(&r, &reference[..5], 10), vec![12, 13]);
+    }
+
+    #[test]
+    fn test_indels_in_window() {
+        let r = record(b"r\t0\tchr1\t12\t60\t1S2M1I2M2D2M\t*\t0\t0\tTCTAGANC\t*");
+        // insertion before 13, deletion of 15 and 16.
+        assert_eq!(indels_in_window(&r, 13, 0), 1);
+        assert_eq!(indels_in_window(&r, 16, 0), 1);
+        assert_eq!(indels_in_window(&r, 11, 1), 0);
+        assert_eq!(indels_in_window(&r, 11, 2), 1);
+        assert_eq!(indels_in_window(&r, 14, 1), 2);
+        assert_eq!(indels_in_window(&r, 18, 1), 0);
+    }
+
+    #[test]
+    fn test_n_proportion() {
+        let fwd = record(b"r\t0\tchr1\t12\t60\t6M\t*\t0\t0\tNNACGN\t*");
+        let rev = record(b"r\t16\tchr1\t12\t60\t6M\t*\t0\t0\tNNACGN\t*");
+        assert_eq!(n_proportion(&fwd, 2, true), 1.0);
+        assert_eq!(n_proportion(&fwd, 4, false), 0.25);
+        assert_eq!(n_proportion(&rev, 2, true), 0.5);
+        assert_eq!(n_proportion(&rev, 2, false), 1.0);
+        // more bases than the read has and none at all.
+        assert_eq!(n_proportion(&fwd, 100, true), 0.5);
+        assert_eq!(n_proportion(&fwd, 0, true), 0.0);
+    }
+
+    #[test]
+    fn test_mate_end() {
+        // the mate's CIGAR is used when it is given in MC.
+        let r = record(b"r\t99\tchr1\t11\t60\t4M\t=\t13\t8\tACGT\t*\tMC:Z:2M1D3M");
+        assert_eq!(mate_end(&r), Some(18));
+        assert!(overlaps_mate_at(&r, 12) && overlaps_mate_at(&r, 17));
+        assert!(!overlaps_mate_at(&r, 11) && !overlaps_mate_at(&r, 18));
+        // otherwise from the insert size for a mate after the read ...
+        let r = record(b"r\t99\tchr1\t11\t60\t4M\t=\t13\t7\tACGT\t*");
+        assert_eq!(mate_end(&r), Some(17));
+        // ... or the length of the read for a mate before it.
+        let r = record(b"r\t147\tchr1\t13\t60\t4M\t=\t11\t-6\tACGT\t*");
+        assert_eq!(mate_end(&r), Some(14));
+        let r = record(b"r\t97\tchr1\t11\t60\t4M\tchr2\t13\t0\tACGT\t*");
+        assert!(is_chimeric(&r));
+        assert_eq!(mate_end(&r), None);
+    }
+
+    #[test]
+    fn test_cigar_lengths() {
+        let r = record(b"r\t0\tchr1\t12\t60\t2H1S2M1I2=2D1X3N2M\t*\t0\t0\tTCTAGANCA\t*");
+        assert_eq!(aligned_length(&r), 7);
+        assert_eq!(max_indel_length(&r), 2);
+        let r = record(b"r\t0\tchr1\t12\t60\t4M\t*\t0\t0\tTCTA\t*");
+        assert_eq!((aligned_length(&r), max_indel_length(&r)), (4, 0));
+    }
+}
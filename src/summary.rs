@@ -0,0 +1,378 @@
+use crate::cached_faidx::CachedFaidx;
+use crate::position::PbrPosition;
+use crate::processor::BasicProcessor;
+use anyhow::Result;
+use rust_htslib::bam::HeaderView;
+use rust_lapper::{Interval, Lapper};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+/// Callable bases and depth summed over a region.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct RegionSummary {
+    pub(crate) chrom: String,
+    pub(crate) start: u32,
+    pub(crate) stop: u32,
+    /// number of positions that passed the filters.
+    pub(crate) callable: u64,
+    /// sum of the filtered depth (over all samples) at callable positions.
+    pub(crate) depth: u64,
+    /// callable positions by reference base: A, C, G, T and other (N or no --fasta).
+    pub(crate) ref_bases: [u64; 5],
+    /// callable positions by reference trinucleotide with --trinucleotides.
+    pub(crate) trinucleotides: [u64; 32],
+}
+
+/// Aggregates positions into the BED intervals or chromosomes that contain them.
+pub(crate) struct Summarizer {
+    regions: Vec<RegionSummary>,
+    /// intervals for each chromosome with the index of their region as the value.
+    intervals: HashMap<String, Lapper<u32, usize>>,
+    /// the reference used to count the trinucleotides of each region.
+    fai: Option<CachedFaidx>,
+}
+
+impl Summarizer {
+    /// One region for each chromosome in the header.
+    pub(crate) fn by_chromosome(header: &HeaderView) -> Self {
+        let intervals = (0..header.target_count())
+            .map(|tid| {
+                vec![Interval {
+                    start: 0,
+                    stop: header.target_len(tid).unwrap_or(0) as u32,
+                    val: (),
+                }]
+            })
+            .map(Lapper::new)
+            .collect();
+        Self::new(header, intervals)
+    }
+
+    /// One region for each (merged) interval in the BED file.
+    pub(crate) fn from_bed(header: &HeaderView, bed: &PathBuf) -> Result<Self> {
+        let intervals = BasicProcessor::bed_to_intervals(header, bed, true)?;
+        Ok(Self::new(header, intervals))
+    }
+
+    fn new(header: &HeaderView, intervals: Vec<Lapper<u32, ()>>) -> Self {
+        let mut regions = vec![];
+        let mut by_chrom = HashMap::new();
+        for (tid, lapper) in intervals.into_iter().enumerate() {
+            let chrom = String::from_utf8_lossy(header.tid2name(tid as u32)).to_string();
+            // intervals are sorted by start in the lapper.
+            let ivs = lapper
+                .iter()
+                .map(|iv| {
+                    regions.push(RegionSummary {
+                        chrom: chrom.clone(),
+                        start: iv.start,
+                        stop: iv.stop,
+                        ..Default::default()
+                    });
+                    Interval {
+                        start: iv.start,
+                        stop: iv.stop,
+                        val: regions.len() - 1,
+                    }
+                })
+                .collect();
+            by_chrom.insert(chrom, Lapper::new(ivs));
+        }
+        Summarizer {
+            regions,
+            intervals: by_chrom,
+            fai: None,
+        }
+    }
+
+    /// Also count the callable positions of each region by reference
+    /// trinucleotide.
+    pub(crate) fn with_trinucleotides(mut self, fai: CachedFaidx) -> Self {
+        self.fai = Some(fai);
+        self
+    }
+
+    /// Add a position that passed the filters to the region that contains it.
+    pub(crate) fn add(&mut self, ps: &[PbrPosition]) -> Result<()> {
+        let p = &ps[0];
+        let Some(lapper) = self.intervals.get(&p.ref_seq) else {
+            return Ok(());
+        };
+        let Some(iv) = lapper.find(p.pos, p.pos + 1).next() else {
+            return Ok(());
+        };
+        let region = &mut self.regions[iv.val];
+        region.callable += 1;
+        region.depth += ps.iter().map(|p| p.depth as u64).sum::<u64>();
+        let base = match p.ref_base.map(|b| b.to_ascii_uppercase()) {
+            Some('A') => 0,
+            Some('C') => 1,
+            Some('G') => 2,
+            Some('T') => 3,
+            _ => 4,
+        };
+        region.ref_bases[base] += 1;
+        if let Some(fai) = &mut self.fai {
+            if let Some(i) = context_index(fai, p)? {
+                region.trinucleotides[i] += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write a row for each region. With --trinucleotides, a column for each
+    /// context follows the reference bases.
+    pub(crate) fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        write!(out, "#chrom\tstart\tstop\tcallable\tdepth\tA\tC\tG\tT\tN")?;
+        if self.fai.is_some() {
+            for context in contexts() {
+                write!(out, "\t{}", std::str::from_utf8(&context).unwrap())?;
+            }
+        }
+        writeln!(out)?;
+        for r in &self.regions {
+            write!(
+                out,
+                "{}\t{}\t{}\t{}\t{}",
+                r.chrom, r.start, r.stop, r.callable, r.depth
+            )?;
+            for n in r.ref_bases {
+                write!(out, "\t{}", n)?;
+            }
+            if self.fai.is_some() {
+                for context in contexts() {
+                    let i = trinucleotide_index(&context).unwrap();
+                    write!(out, "\t{}", r.trinucleotides[i])?;
+                }
+            }
+            writeln!(out)?;
+        }
+        out.flush()
+    }
+}
+
+const BASES: [u8; 4] = *b"ACGT";
+
+/// Index of the pyrimidine-centered trinucleotide in the table of 32 contexts,
+/// ordered by center (C, T), then 5' base, then 3' base. Contexts with a purine
+/// at the center are reverse-complemented. None if any base is not A, C, G or T.
+pub(crate) fn trinucleotide_index(context: &[u8]) -> Option<usize> {
+    let base = |b: &u8| BASES.iter().position(|x| *x == b.to_ascii_uppercase());
+    let (five, center, three) = match context {
+        [five, center, three] => (base(five)?, base(center)?, base(three)?),
+        _ => return None,
+    };
+    // with ACGT ordering, the complement of base i is 3 - i.
+    let (five, center, three) = match center {
+        1 | 3 => (five, center, three),
+        _ => (3 - three, 3 - center, 3 - five),
+    };
+    Some((center / 2) * 16 + five * 4 + three)
+}
+
+/// The 32 pyrimidine-centered trinucleotides in the order they are written.
+fn contexts() -> impl Iterator<Item = [u8; 3]> {
+    [b'C', b'T'].into_iter().flat_map(|center| {
+        BASES
+            .into_iter()
+            .flat_map(move |five| BASES.map(|three| [five, center, three]))
+    })
+}
+
+/// Index of the reference trinucleotide centered on the position. None at the
+/// ends of the chromosome or if any base is not A, C, G or T.
+fn context_index(fai: &mut CachedFaidx, p: &PbrPosition) -> Result<Option<usize>> {
+    if p.pos == 0 {
+        return Ok(None);
+    }
+    let pos = p.pos as usize;
+    Ok(trinucleotide_index(fai.fetch_seq(
+        &p.ref_seq,
+        pos - 1,
+        pos + 1,
+    )?))
+}
+
+/// Callable positions and their depth by reference trinucleotide, e.g. for
+/// SBS-96 mutation signature opportunities.
+pub(crate) struct TrinucleotideCounts {
+    fai: CachedFaidx,
+    callable: [u64; 32],
+    depth: [u64; 32],
+    /// callable positions without a full A/C/G/T context.
+    skipped: u64,
+}
+
+impl TrinucleotideCounts {
+    pub(crate) fn new(fai: CachedFaidx) -> Self {
+        TrinucleotideCounts {
+            fai,
+            callable: [0; 32],
+            depth: [0; 32],
+            skipped: 0,
+        }
+    }
+
+    pub(crate) fn add(&mut self, ps: &[PbrPosition]) -> Result<()> {
+        match context_index(&mut self.fai, &ps[0])? {
+            Some(i) => {
+                self.callable[i] += 1;
+                self.depth[i] += ps.iter().map(|p| p.depth as u64).sum::<u64>();
+            }
+            None => self.skipped += 1,
+        }
+        Ok(())
+    }
+
+    pub(crate) fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(out, "#context\tcallable\tdepth")?;
+        for context in contexts() {
+            let i = trinucleotide_index(&context).unwrap();
+            writeln!(
+                out,
+                "{}\t{}\t{}",
+                std::str::from_utf8(&context).unwrap(),
+                self.callable[i],
+                self.depth[i]
+            )?;
+        }
+        if self.skipped > 0 {
+            eprintln!(
+                "{} callable positions without an A/C/G/T trinucleotide context were not counted",
+                self.skipped
+            );
+        }
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{header_view, position};
+    use tempfile::NamedTempFile;
+
+    fn header() -> HeaderView {
+        header_view(&[("chr1", 1000), ("chr2", 500)])
+    }
+
+    #[test]
+    fn test_summary_by_chromosome() -> Result<()> {
+        let mut s = Summarizer::by_chromosome(&header());
+        s.add(&[position("chr1", 10, 5, Some('A'))])?;
+        s.add(&[
+            position("chr1", 11, 3, Some('c')),
+            position("chr1", 11, 4, Some('c')),
+        ])?;
+        s.add(&[position("chr2", 3, 2, None)])?;
+        let mut out = vec![];
+        s.write(&mut out)?;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "#chrom\tstart\tstop\tcallable\tdepth\tA\tC\tG\tT\tN\n\
+             chr1\t0\t1000\t2\t12\t1\t1\t0\t0\t0\n\
+             chr2\t0\t500\t1\t2\t0\t0\t0\t0\t1\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_summary_from_bed() -> Result<()> {
+        let mut bed = NamedTempFile::new()?;
+        bed.write_all(b"chr1\t100\t200\nchr1\t10\t20\nchr1\t15\t30\nchr2\t0\t5\n")?;
+        let mut s = Summarizer::from_bed(&header(), &bed.path().to_path_buf())?;
+        s.add(&[position("chr1", 29, 4, Some('G'))])?;
+        s.add(&[position("chr1", 150, 3, Some('T'))])?;
+        // outside of the intervals.
+        s.add(&[position("chr1", 50, 3, Some('T'))])?;
+        let regions = &s.regions;
+        assert_eq!(regions.len(), 3);
+        assert_eq!((regions[0].start, regions[0].stop), (10, 30));
+        assert_eq!((regions[0].callable, regions[0].depth), (1, 4));
+        assert_eq!(regions[0].ref_bases, [0, 0, 1, 0, 0]);
+        assert_eq!((regions[1].start, regions[1].callable), (100, 1));
+        assert_eq!(
+            (regions[2].chrom.as_str(), regions[2].callable),
+            ("chr2", 0)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_trinucleotide_index() {
+        assert_eq!(trinucleotide_index(b"ACA"), Some(0));
+        assert_eq!(trinucleotide_index(b"tct"), Some(3 * 4 + 3));
+        assert_eq!(trinucleotide_index(b"ATA"), Some(16));
+        // reverse complement of GCA is TGC
+        assert_eq!(trinucleotide_index(b"TGC"), trinucleotide_index(b"GCA"));
+        assert_eq!(trinucleotide_index(b"AAA"), trinucleotide_index(b"TTT"));
+        assert_eq!(trinucleotide_index(b"ANA"), None);
+        assert_eq!(trinucleotide_index(b"AC"), None);
+        let mut seen: Vec<_> = BASES
+            .iter()
+            .flat_map(|a| BASES.iter().flat_map(move |b| BASES.map(|c| [*a, *b, c])))
+            .map(|ctx| trinucleotide_index(&ctx).unwrap())
+            .collect();
+        seen.sort();
+        seen.dedup();
+        assert_eq!(seen, (0..32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_trinucleotide_counts() -> Result<()> {
+        let fai = CachedFaidx::new(format!("{}/test/test_cram.fa", env!("CARGO_MANIFEST_DIR")))?;
+        let mut counts = TrinucleotideCounts::new(fai);
+        // chr1 starts with GGGCACAGCC.
+        for (pos, depth) in [(0, 1), (1, 2), (3, 3), (4, 4), (5, 5), (119, 6)] {
+            counts.add(&[position("chr1", pos, depth, None)])?;
+        }
+        let ccc = trinucleotide_index(b"CCC").unwrap();
+        let gca = trinucleotide_index(b"GCA").unwrap();
+        let aca = trinucleotide_index(b"ACA").unwrap();
+        let gtg = trinucleotide_index(b"GTG").unwrap();
+        assert_eq!(counts.callable[ccc], 1);
+        assert_eq!((counts.callable[gca], counts.depth[gca]), (1, 3));
+        assert_eq!((counts.callable[aca], counts.depth[aca]), (1, 5));
+        assert_eq!((counts.callable[gtg], counts.depth[gtg]), (1, 4));
+        // the first and last base of chr1 have no context.
+        assert_eq!(counts.skipped, 2);
+
+        let mut out = vec![];
+        counts.write(&mut out)?;
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().count(), 33);
+        assert!(out.contains("\nGCA\t1\t3\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_summary_trinucleotides() -> Result<()> {
+        let fai = CachedFaidx::new(format!("{}/test/test_cram.fa", env!("CARGO_MANIFEST_DIR")))?;
+        let mut bed = NamedTempFile::new()?;
+        bed.write_all(b"chr1\t0\t4\nchr1\t5\t10\n")?;
+        let mut s =
+            Summarizer::from_bed(&header(), &bed.path().to_path_buf())?.with_trinucleotides(fai);
+        // chr1 starts with GGGCACAGCC.
+        for pos in [0, 1, 3, 5, 6] {
+            s.add(&[position("chr1", pos, 1, None)])?;
+        }
+        let index = |context: &[u8]| trinucleotide_index(context).unwrap();
+        let (first, second) = (&s.regions[0], &s.regions[1]);
+        // the first base has no context.
+        assert_eq!(first.callable, 3);
+        assert_eq!(first.trinucleotides.iter().sum::<u64>(), 2);
+        assert_eq!(first.trinucleotides[index(b"CCC")], 1);
+        assert_eq!(first.trinucleotides[index(b"GCA")], 1);
+        assert_eq!(second.trinucleotides[index(b"CTG")], 1);
+        assert_eq!(second.trinucleotides[index(b"ACA")], 1);
+
+        let mut out = vec![];
+        s.write(&mut out)?;
+        let out = String::from_utf8(out).unwrap();
+        let header = out.lines().next().unwrap();
+        assert_eq!(header.split('\t').count(), 10 + 32);
+        assert!(header.ends_with("\tTTG\tTTT"), "{}", header);
+        Ok(())
+    }
+}
@@ -0,0 +1,40 @@
+use crate::position::PbrPosition;
+use perbase_lib::position::pileup_position::PileupPosition;
+use rust_htslib::bam::{header::HeaderRecord, record::Record, Header, HeaderView};
+
+/// The sequences of the header that SAM records are parsed with.
+pub(crate) const SEQUENCES: &[(&str, u32)] = &[("chr1", 1_000_000), ("chr2", 1_000_000)];
+
+/// A header with an @SQ line for each sequence and its length.
+pub(crate) fn header(sequences: &[(&str, u32)]) -> Header {
+    let mut header = Header::new();
+    for (name, len) in sequences {
+        let mut sq = HeaderRecord::new(b"SQ");
+        sq.push_tag(b"SN", name);
+        sq.push_tag(b"LN", len);
+        header.push_record(&sq);
+    }
+    header
+}
+
+pub(crate) fn header_view(sequences: &[(&str, u32)]) -> HeaderView {
+    HeaderView::from_header(&header(sequences))
+}
+
+/// A record parsed from a SAM line on the `SEQUENCES`.
+pub(crate) fn record(sam: &[u8]) -> Record {
+    Record::from_sam(&header_view(SEQUENCES), sam).expect("valid SAM")
+}
+
+/// A position where all of the reads have an A.
+pub(crate) fn position(chrom: &str, pos: u32, depth: u32, ref_base: Option<char>) -> PbrPosition {
+    PileupPosition {
+        ref_seq: chrom.into(),
+        pos,
+        depth,
+        a: depth,
+        ref_base,
+        ..Default::default()
+    }
+    .into()
+}
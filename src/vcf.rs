@@ -0,0 +1,253 @@
+use crate::output::{Formatted, PositionWriter};
+use crate::position::PbrPosition;
+use rust_htslib::bam::HeaderView;
+use std::io::{self, Write};
+
+/// Consecutive positions with similar depth that are written as a single
+/// reference block.
+struct Block {
+    chrom: String,
+    /// 0-based start and inclusive end.
+    start: u32,
+    end: u32,
+    ref_base: char,
+    min_dp: Vec<u32>,
+    max_dp: Vec<u32>,
+    sum_dp: Vec<u64>,
+}
+
+impl Block {
+    fn new(ps: &[PbrPosition]) -> Self {
+        Block {
+            chrom: ps[0].ref_seq.clone(),
+            start: ps[0].pos,
+            end: ps[0].pos,
+            ref_base: ref_base(&ps[0]),
+            min_dp: ps.iter().map(|p| p.depth).collect(),
+            max_dp: ps.iter().map(|p| p.depth).collect(),
+            sum_dp: ps.iter().map(|p| p.depth as u64).collect(),
+        }
+    }
+
+    /// Extend the block with the next position if it is adjacent and the
+    /// depth of every sample stays within `tolerance` (a fraction) of the
+    /// minimum depth in the block.
+    fn extend(&mut self, ps: &[PbrPosition], tolerance: f64) -> bool {
+        if ps[0].ref_seq != self.chrom || ps[0].pos != self.end + 1 {
+            return false;
+        }
+        let similar = ps.iter().enumerate().all(|(i, p)| {
+            let min = self.min_dp[i].min(p.depth) as f64;
+            let max = self.max_dp[i].max(p.depth) as f64;
+            max <= min * (1.0 + tolerance)
+        });
+        if !similar {
+            return false;
+        }
+        self.end = ps[0].pos;
+        for (i, p) in ps.iter().enumerate() {
+            self.min_dp[i] = self.min_dp[i].min(p.depth);
+            self.max_dp[i] = self.max_dp[i].max(p.depth);
+            self.sum_dp[i] += p.depth as u64;
+        }
+        true
+    }
+}
+
+fn ref_base(p: &PbrPosition) -> char {
+    p.ref_base.map(|b| b.to_ascii_uppercase()).unwrap_or('N')
+}
+
+/// Writes positions as VCF records with the filtered depth and base counts of
+/// each sample or, with a block tolerance, as gVCF-like reference blocks.
+pub(crate) struct VcfWriter<W: Write> {
+    out: W,
+    /// the header lines, written with the first record.
+    header: Option<String>,
+    /// the fraction by which depths may vary within a reference block.
+    block_tolerance: Option<f64>,
+    block: Option<Block>,
+}
+
+impl<W: Write> VcfWriter<W> {
+    pub(crate) fn new(
+        out: W,
+        header: &HeaderView,
+        samples: &[String],
+        reference: Option<&str>,
+        block_tolerance: Option<f64>,
+    ) -> Self {
+        let mut lines = vec![
+            String::from("##fileformat=VCFv4.2"),
+            format!("##source=pbr {}", env!("CARGO_PKG_VERSION")),
+        ];
+        if let Some(reference) = reference {
+            lines.push(format!("##reference={}", reference));
+        }
+        for tid in 0..header.target_count() {
+            lines.push(format!(
+                "##contig=<ID={},length={}>",
+                String::from_utf8_lossy(header.tid2name(tid)),
+                header.target_len(tid).unwrap_or(0)
+            ));
+        }
+        if block_tolerance.is_some() {
+            lines.push(String::from(
+                "##INFO=<ID=END,Number=1,Type=Integer,Description=\"End position of the reference block\">",
+            ));
+            lines.push(String::from(
+                "##FORMAT=<ID=DP,Number=1,Type=Integer,Description=\"Mean filtered depth in the reference block\">",
+            ));
+            lines.push(String::from(
+                "##FORMAT=<ID=MIN_DP,Number=1,Type=Integer,Description=\"Minimum filtered depth in the reference block\">",
+            ));
+        } else {
+            lines.push(String::from(
+                "##FORMAT=<ID=DP,Number=1,Type=Integer,Description=\"Filtered depth\">",
+            ));
+            lines.push(String::from(
+                "##FORMAT=<ID=BC,Number=5,Type=Integer,Description=\"Filtered count of A, C, G, T and N\">",
+            ));
+        }
+        lines.push(format!(
+            "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\t{}",
+            samples.join("\t")
+        ));
+        VcfWriter {
+            out,
+            header: Some(lines.join("\n")),
+            block_tolerance,
+            block: None,
+        }
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        if let Some(header) = self.header.take() {
+            writeln!(self.out, "{}", header)?;
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self, block: &Block) -> io::Result<()> {
+        write!(
+            self.out,
+            "{}\t{}\t.\t{}\t.\t.\t.\tEND={}\tDP:MIN_DP",
+            block.chrom,
+            block.start + 1,
+            block.ref_base,
+            block.end + 1
+        )?;
+        let n = (block.end - block.start + 1) as f64;
+        for (sum, min) in block.sum_dp.iter().zip(&block.min_dp) {
+            write!(self.out, "\t{}:{}", (*sum as f64 / n).round() as u64, min)?;
+        }
+        writeln!(self.out)
+    }
+}
+
+impl<W: Write> PositionWriter for VcfWriter<W> {
+    fn write(&mut self, ps: &[PbrPosition], _formatted: &Formatted) -> io::Result<()> {
+        self.write_header()?;
+        let Some(tolerance) = self.block_tolerance else {
+            let p = &ps[0];
+            write!(
+                self.out,
+                "{}\t{}\t.\t{}\t.\t.\t.\t.\tDP:BC",
+                p.ref_seq,
+                p.pos + 1,
+                ref_base(p)
+            )?;
+            for p in ps {
+                write!(
+                    self.out,
+                    "\t{}:{},{},{},{},{}",
+                    p.depth, p.a, p.c, p.g, p.t, p.n
+                )?;
+            }
+            return writeln!(self.out);
+        };
+        if let Some(block) = self.block.as_mut() {
+            if block.extend(ps, tolerance) {
+                return Ok(());
+            }
+        }
+        if let Some(block) = self.block.replace(Block::new(ps)) {
+            self.write_block(&block)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.write_header()?;
+        if let Some(block) = self.block.take() {
+            self.write_block(&block)?;
+        }
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{header_view, position};
+
+    fn header() -> HeaderView {
+        header_view(&[("chr1", 1000)])
+    }
+
+    fn records(out: Vec<u8>) -> Vec<String> {
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .filter(|l| !l.starts_with('#'))
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_vcf_records() -> io::Result<()> {
+        let mut out = vec![];
+        let samples = [String::from("tumor"), String::from("normal")];
+        let mut w = VcfWriter::new(&mut out, &header(), &samples, None, None);
+        w.write(
+            &[
+                position("chr1", 9, 5, Some('a')),
+                position("chr1", 9, 0, Some('a')),
+            ],
+            &Formatted::Default,
+        )?;
+        w.finish()?;
+        let text = String::from_utf8(out.clone()).unwrap();
+        assert!(text.starts_with("##fileformat=VCFv4.2\n"));
+        assert!(text.contains("##contig=<ID=chr1,length=1000>\n"));
+        assert!(text.contains("FORMAT\ttumor\tnormal\n"));
+        assert_eq!(
+            records(out),
+            vec!["chr1\t10\t.\tA\t.\t.\t.\t.\tDP:BC\t5:5,0,0,0,0\t0:0,0,0,0,0"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_vcf_blocks() -> io::Result<()> {
+        let mut out = vec![];
+        let mut w = VcfWriter::new(&mut out, &header(), &[String::from("s")], None, Some(0.2));
+        for (pos, depth) in [(10, 10), (11, 12), (12, 11), (13, 13), (15, 13), (16, 30)] {
+            w.write(
+                &[position("chr1", pos, depth, Some('a'))],
+                &Formatted::Default,
+            )?;
+        }
+        w.finish()?;
+        assert_eq!(
+            records(out),
+            vec![
+                "chr1\t11\t.\tA\t.\t.\t.\tEND=13\tDP:MIN_DP\t11:10",
+                "chr1\t14\t.\tA\t.\t.\t.\tEND=14\tDP:MIN_DP\t13:13",
+                "chr1\t16\t.\tA\t.\t.\t.\tEND=16\tDP:MIN_DP\t13:13",
+                "chr1\t17\t.\tA\t.\t.\t.\tEND=17\tDP:MIN_DP\t30:30",
+            ]
+        );
+        Ok(())
+    }
+}